@@ -0,0 +1,135 @@
+//! The retry policy for outgoing messages.
+//!
+//! When a frame is not acknowledged, it's resent after a delay that doubles
+//! with each attempt. The delay is randomized, so that devices that failed
+//! to send at the same time (because they collided) don't collide again.
+
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    /// How many times to try sending a frame before giving up.
+    pub max_attempts: u8,
+    /// The delay (in milliseconds) before the first retry.
+    pub base_delay: u16,
+    /// The upper limit (in milliseconds) for the delay between retries.
+    pub max_delay: u16,
+    /// How long (in milliseconds) after queueing the frame to give up.
+    /// Zero means no deadline.
+    pub deadline: u16,
+}
+
+impl RetryPolicy {
+    pub const DEFAULT: Self = Self {
+        max_attempts: 15,
+        base_delay: 2,
+        max_delay: 100,
+        deadline: 0,
+    };
+
+    #[must_use]
+    pub const fn is_valid(&self) -> bool {
+        self.max_attempts > 0 && self.base_delay <= self.max_delay
+    }
+
+    /// How long (in milliseconds) to wait before the given retry attempt.
+    ///
+    /// The random value is used for the jitter: the delay is picked
+    /// between half and the full exponential delay.
+    #[must_use]
+    pub fn delay(&self, attempt: u8, random: u32) -> u64 {
+        let max_delay = u64::from(self.max_delay);
+        let shift = u32::from(attempt.saturating_sub(1)).min(16);
+        let delay = (u64::from(self.base_delay) << shift).min(max_delay);
+        let half = delay / 2;
+        half + u64::from(random) % (delay - half + 1)
+    }
+
+    /// Check if the frame queued at the given time is past the deadline.
+    #[must_use]
+    pub const fn expired(&self, queued_at: u64, now: u64) -> bool {
+        self.deadline != 0 && now.saturating_sub(queued_at) > self.deadline as u64
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn policy(base_delay: u16, max_delay: u16) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 15,
+            base_delay,
+            max_delay,
+            deadline: 0,
+        }
+    }
+
+    /// The longest delay for the attempt: the random value hits the top of the jitter range.
+    fn full(p: RetryPolicy, attempt: u8) -> u64 {
+        let delay = p.delay(attempt, u32::MAX);
+        (0..=u32::from(p.max_delay))
+            .map(|random| p.delay(attempt, random))
+            .fold(delay, u64::max)
+    }
+
+    #[test]
+    fn exponential_growth() {
+        let p = policy(2, 1000);
+        let delays: Vec<_> = (1..=6).map(|attempt| full(p, attempt)).collect();
+        assert_eq!(delays, [2, 4, 8, 16, 32, 64]);
+        // The first attempt is not a retry but uses the base delay as well.
+        assert_eq!(full(p, 0), 2);
+    }
+
+    #[test]
+    fn clamp_to_max_delay() {
+        let p = policy(2, 100);
+        assert_eq!(full(p, 6), 64);
+        assert_eq!(full(p, 7), 100);
+        assert_eq!(full(p, 15), 100);
+        assert_eq!(p.delay(15, 0), 50);
+    }
+
+    #[test]
+    fn jitter_between_half_and_full() {
+        let p = policy(2, 1000);
+        // 2 << 5 = 64
+        let delays: Vec<_> = (0..1000).map(|random| p.delay(6, random)).collect();
+        assert!(delays.iter().all(|d| (32..=64).contains(d)));
+        assert_eq!(delays.iter().min(), Some(&32));
+        assert_eq!(delays.iter().max(), Some(&64));
+        assert_eq!(p.delay(6, 0), 32);
+        assert_eq!(p.delay(6, 32), 64);
+        // The range wraps around.
+        assert_eq!(p.delay(6, 33), 32);
+    }
+
+    #[test]
+    fn shift_saturates() {
+        let p = policy(1, u16::MAX);
+        let top = u64::from(u16::MAX);
+        assert_eq!(p.delay(17, 0), top / 2);
+        // Attempts past 17 don't overflow the shift.
+        assert_eq!(p.delay(u8::MAX, 0), top / 2);
+        assert_eq!(p.delay(u8::MAX, u32::MAX), p.delay(17, u32::MAX));
+        let p = policy(u16::MAX, u16::MAX);
+        assert_eq!(full(p, u8::MAX), top);
+    }
+
+    #[test]
+    fn deadline() {
+        let mut p = policy(2, 100);
+        // Zero means no deadline.
+        assert!(!p.expired(0, u64::MAX));
+        p.deadline = 10;
+        assert!(!p.expired(5, 15));
+        assert!(p.expired(5, 16));
+        // The clock is never behind the queue time, but it must not underflow.
+        assert!(!p.expired(20, 5));
+    }
+}
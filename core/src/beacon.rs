@@ -0,0 +1,143 @@
+//! Discovery beacons.
+//!
+//! Devices looking for peers broadcast a beacon describing the device
+//! and the game it's running. Beacons from other games can be ignored,
+//! so that two different games played nearby don't connect to each other.
+use crate::pairing::KEY_SIZE;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// The first bytes of every beacon.
+const MAGIC: &[u8; 4] = b"FFZB";
+/// The version of the network protocol. Devices with a different version are ignored.
+pub const PROTOCOL_VERSION: u8 = 3;
+/// Max length in bytes of the device name.
+pub const MAX_NAME: usize = 16;
+/// Max length in bytes of the app ID.
+pub const MAX_APP: usize = 32;
+/// Max number of discovered devices to remember.
+pub const MAX_DISCOVERED: usize = 16;
+
+type Addr = [u8; 6];
+
+#[derive(Clone)]
+pub struct Beacon {
+    pub protocol: u8,
+    pub firmware: (u8, u8, u8),
+    pub name: String,
+    /// The full ID of the running app, like "lux.snek".
+    pub app: String,
+    /// The ID of the game session. Zero if the device is not in a lobby.
+    pub lobby: u32,
+    /// The Wi-Fi channel used by the device. Devices joining the lobby must switch to it.
+    pub channel: u8,
+    /// The public key for pairing.
+    pub key: [u8; KEY_SIZE],
+}
+
+impl Beacon {
+    /// Check if the device sending the beacon can be connected to.
+    ///
+    /// If `same_app` is true, only devices running the same app are accepted.
+    #[must_use]
+    pub fn accepts(&self, other: &Self, same_app: bool) -> bool {
+        other.protocol == self.protocol && (!same_app || other.app == self.app)
+    }
+
+    #[must_use]
+    pub fn encode(&self) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(MAGIC.len() + 11 + KEY_SIZE + MAX_NAME + MAX_APP);
+        raw.extend_from_slice(MAGIC);
+        raw.push(self.protocol);
+        raw.extend_from_slice(&<[u8; 3]>::from(self.firmware));
+        raw.extend_from_slice(&self.lobby.to_le_bytes());
+        raw.push(self.channel);
+        raw.extend_from_slice(&self.key);
+        push_str(&mut raw, &self.name, MAX_NAME);
+        push_str(&mut raw, &self.app, MAX_APP);
+        raw
+    }
+
+    /// Parse the beacon. Returns None if the payload is not a valid beacon.
+    #[must_use]
+    pub fn decode(raw: &[u8]) -> Option<Self> {
+        let raw = raw.strip_prefix(MAGIC)?;
+        let (&[protocol, major, minor, patch], raw) = raw.split_first_chunk()?;
+        let (lobby, raw) = raw.split_first_chunk()?;
+        let (&channel, raw) = raw.split_first()?;
+        let (key, raw) = raw.split_first_chunk()?;
+        let (name, raw) = take_str(raw, MAX_NAME)?;
+        let (app, raw) = take_str(raw, MAX_APP)?;
+        if !raw.is_empty() {
+            return None;
+        }
+        Some(Self {
+            protocol,
+            firmware: (major, minor, patch),
+            name,
+            app,
+            lobby: u32::from_le_bytes(*lobby),
+            channel,
+            key: *key,
+        })
+    }
+}
+
+/// Write the length-prefixed string, truncated to the max length.
+fn push_str(raw: &mut Vec<u8>, s: &str, max: usize) {
+    let mut len = s.len().min(max);
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    raw.push(u8::try_from(len).unwrap_or_default());
+    raw.extend_from_slice(&s.as_bytes()[..len]);
+}
+
+fn take_str(raw: &[u8], max: usize) -> Option<(String, &[u8])> {
+    let (&len, raw) = raw.split_first()?;
+    let len = usize::from(len);
+    if len > max || len > raw.len() {
+        return None;
+    }
+    let (s, raw) = raw.split_at(len);
+    let s = core::str::from_utf8(s).ok()?;
+    Some((String::from(s), raw))
+}
+
+/// Devices whose beacons were received recently.
+#[derive(Default)]
+pub struct Discovered {
+    devices: Vec<(Addr, Beacon)>,
+}
+
+impl Discovered {
+    /// Remember the beacon, replacing the previous one from the same device.
+    ///
+    /// If the list is full, the device seen the longest time ago is forgotten.
+    pub fn insert(&mut self, addr: Addr, beacon: Beacon) {
+        self.devices.retain(|(a, _)| *a != addr);
+        if self.devices.len() >= MAX_DISCOVERED {
+            self.devices.remove(0);
+        }
+        self.devices.push((addr, beacon));
+    }
+
+    #[must_use]
+    pub fn get(&self, index: usize) -> Option<&(Addr, Beacon)> {
+        self.devices.get(index)
+    }
+
+    #[must_use]
+    pub const fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.devices.clear();
+    }
+}
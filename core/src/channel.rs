@@ -0,0 +1,207 @@
+//! Picking the least congested Wi-Fi channel for ESP-NOW.
+//!
+//! 2.4 GHz channels are 5 MHz apart but a transmission is about 20 MHz wide,
+//! so a busy channel also disturbs up to 4 channels on each side.
+//! Each access point found by a scan adds to the congestion of its channel
+//! and, with a smaller weight, to the neighbor channels.
+//!
+//! Devices that don't pick a channel themselves sweep all channels
+//! looking for a beacon and switch to the channel announced in it.
+//! If nobody is found, they stay on the default channel.
+
+/// The channel used until another one is selected.
+pub const DEFAULT_CHANNEL: u8 = 6;
+/// The highest channel allowed in all regions.
+pub const MAX_CHANNEL: u8 = 13;
+/// How many neighbor channels on each side are affected by a transmission.
+const OVERLAP: u8 = 4;
+/// Channels that don't overlap with each other, preferred when congestion is the same.
+const PREFERRED: [u8; 3] = [1, 6, 11];
+/// How long (in milliseconds) to listen for beacons on each channel when sweeping.
+const DWELL: u64 = 250;
+/// How many times to visit every channel before giving up the sweep.
+const ROUNDS: u8 = 2;
+
+#[must_use]
+pub const fn is_valid(channel: u8) -> bool {
+    channel >= 1 && channel <= MAX_CHANNEL
+}
+
+#[derive(Default)]
+pub struct Congestion {
+    scores: [u32; MAX_CHANNEL as usize],
+}
+
+impl Congestion {
+    /// Account for an access point on the given channel with the given signal strength (in dBm).
+    pub fn add(&mut self, channel: u8, rssi: i8) {
+        if !is_valid(channel) {
+            return;
+        }
+        // -100 dBm is barely audible, -30 dBm is right next to the device.
+        let strength = u32::from(rssi.saturating_add(100).max(1).unsigned_abs());
+        for other in 1..=MAX_CHANNEL {
+            let distance = channel.abs_diff(other);
+            if distance > OVERLAP {
+                continue;
+            }
+            let weight = u32::from(OVERLAP + 1 - distance);
+            self.scores[usize::from(other - 1)] += strength * weight;
+        }
+    }
+
+    /// The congestion score of the channel. Lower is better.
+    #[must_use]
+    pub fn score(&self, channel: u8) -> u32 {
+        if !is_valid(channel) {
+            return u32::MAX;
+        }
+        self.scores[usize::from(channel - 1)]
+    }
+
+    /// The channel with the lowest congestion.
+    #[must_use]
+    pub fn quietest(&self) -> u8 {
+        let others = (1..=MAX_CHANNEL).filter(|c| !PREFERRED.contains(c));
+        PREFERRED
+            .into_iter()
+            .chain(others)
+            .min_by_key(|&channel| self.score(channel))
+            .unwrap_or(DEFAULT_CHANNEL)
+    }
+}
+
+/// Hopping over all channels looking for beacons.
+pub struct Sweep {
+    channel: u8,
+    /// When to switch to the next channel.
+    hop_at: u64,
+    hops: u8,
+}
+
+impl Sweep {
+    #[must_use]
+    pub const fn new(now: u64) -> Self {
+        Self {
+            channel: 1,
+            hop_at: now + DWELL,
+            hops: 0,
+        }
+    }
+
+    /// The channel to listen on now.
+    #[must_use]
+    pub const fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    /// If all channels have been visited enough times.
+    #[must_use]
+    pub const fn is_done(&self) -> bool {
+        self.hops >= MAX_CHANNEL * ROUNDS
+    }
+
+    /// Get the channel to switch to if it's time to hop.
+    ///
+    /// When the sweep is done, switches back to the default channel.
+    pub const fn poll(&mut self, now: u64) -> Option<u8> {
+        if self.is_done() || now < self.hop_at {
+            return None;
+        }
+        self.hops += 1;
+        self.hop_at = now + DWELL;
+        self.channel = if self.is_done() {
+            DEFAULT_CHANNEL
+        } else {
+            self.channel % MAX_CHANNEL + 1
+        };
+        Some(self.channel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn empty_prefers_non_overlapping() {
+        let congestion = Congestion::default();
+        assert_eq!(congestion.quietest(), 1);
+    }
+
+    #[test]
+    fn neighbors_are_affected() {
+        let mut congestion = Congestion::default();
+        congestion.add(6, -50);
+        assert!(congestion.score(6) > congestion.score(5));
+        assert!(congestion.score(5) > congestion.score(2));
+        assert!(congestion.score(2) > 0);
+        assert_eq!(congestion.score(1), 0);
+        assert_eq!(congestion.score(11), 0);
+        assert_eq!(congestion.score(5), congestion.score(7));
+    }
+
+    #[test]
+    fn stronger_is_worse() {
+        let mut near = Congestion::default();
+        near.add(6, -30);
+        let mut far = Congestion::default();
+        far.add(6, -90);
+        assert!(near.score(6) > far.score(6));
+        // Even barely audible points count.
+        let mut faint = Congestion::default();
+        faint.add(6, -120);
+        assert!(faint.score(6) > 0);
+    }
+
+    #[test]
+    fn invalid_channels() {
+        let mut congestion = Congestion::default();
+        congestion.add(0, -30);
+        congestion.add(14, -30);
+        assert!((1..=MAX_CHANNEL).all(|c| congestion.score(c) == 0));
+        assert_eq!(congestion.score(0), u32::MAX);
+        assert_eq!(congestion.score(14), u32::MAX);
+    }
+
+    #[test]
+    fn quietest() {
+        let mut congestion = Congestion::default();
+        congestion.add(1, -40);
+        congestion.add(6, -40);
+        assert_eq!(congestion.quietest(), 11);
+        // Busy non-overlapping channels make others better.
+        for _ in 0..3 {
+            congestion.add(1, -40);
+            congestion.add(6, -40);
+            congestion.add(11, -40);
+        }
+        let quietest = congestion.quietest();
+        assert!(!PREFERRED.contains(&quietest));
+        for channel in 1..=MAX_CHANNEL {
+            assert!(congestion.score(quietest) <= congestion.score(channel));
+        }
+    }
+
+    #[test]
+    fn sweep_visits_all_channels() {
+        let mut sweep = Sweep::new(0);
+        let mut visited = Vec::from([sweep.channel()]);
+        let mut now = 0;
+        while !sweep.is_done() {
+            assert!(sweep.poll(now + DWELL - 1).is_none());
+            now += DWELL;
+            let channel = sweep.poll(now).unwrap();
+            assert_eq!(channel, sweep.channel());
+            visited.push(channel);
+        }
+        let round: Vec<u8> = (1..=MAX_CHANNEL).collect();
+        assert_eq!(visited.len(), round.len() * usize::from(ROUNDS) + 1);
+        assert_eq!(visited[..round.len()], round);
+        assert_eq!(visited[round.len()..round.len() * 2], round);
+        // Gives up and stays on the default channel.
+        assert_eq!(visited.last(), Some(&DEFAULT_CHANNEL));
+        assert!(sweep.poll(now + DWELL * 10).is_none());
+    }
+}
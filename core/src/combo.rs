@@ -0,0 +1,178 @@
+//! Detection of long presses and button combinations.
+//!
+//! Combos trigger system-level actions on the IO chip itself,
+//! so they work even if the main chip is unresponsive.
+
+/// Max number of combos that can be configured at the same time.
+pub const MAX_COMBOS: usize = 4;
+
+pub const BTN_S: u8 = 1 << 0;
+pub const BTN_MENU: u8 = 1 << 4;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Restart the IO chip.
+    Reboot,
+    /// Switch the IO chip to the factory firmware and restart.
+    FactoryPartition,
+    /// Set the recovery-mode flag for the main chip to read.
+    Recovery,
+}
+
+impl TryFrom<u8> for Action {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, ()> {
+        match value {
+            0 => Ok(Self::Reboot),
+            1 => Ok(Self::FactoryPartition),
+            2 => Ok(Self::Recovery),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Combo {
+    /// Bitmask of buttons that must be held, and no other buttons.
+    pub buttons: u8,
+    /// How long (in milliseconds) the buttons must be held.
+    pub hold: u16,
+    /// If true, the buttons must be held since the device was powered on.
+    pub at_boot: bool,
+    pub action: Action,
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    combo: Combo,
+    /// When the combo buttons were pressed.
+    held_since: Option<u64>,
+    /// If the action has already been triggered for the current hold.
+    fired: bool,
+}
+
+pub struct ComboDetector {
+    slots: [Option<Slot>; MAX_COMBOS],
+    /// The time of the first update, used for combos held at boot.
+    boot: Option<u64>,
+}
+
+impl ComboDetector {
+    /// Create the detector with the default combos:
+    ///
+    /// * Hold menu for 5 seconds to reboot the IO chip.
+    /// * Hold menu and S at boot for 1 second to enter the recovery mode.
+    #[must_use]
+    pub const fn new() -> Self {
+        let mut detector = Self {
+            slots: [None; MAX_COMBOS],
+            boot: None,
+        };
+        detector.set(
+            0,
+            Some(Combo {
+                buttons: BTN_MENU,
+                hold: 5000,
+                at_boot: false,
+                action: Action::Reboot,
+            }),
+        );
+        detector.set(
+            1,
+            Some(Combo {
+                buttons: BTN_MENU | BTN_S,
+                hold: 1000,
+                at_boot: true,
+                action: Action::Recovery,
+            }),
+        );
+        detector
+    }
+
+    /// Set or clear (if None) the combo in the given slot.
+    ///
+    /// Returns false if the slot index is out of range.
+    pub const fn set(&mut self, index: usize, combo: Option<Combo>) -> bool {
+        if index >= MAX_COMBOS {
+            return false;
+        }
+        self.slots[index] = match combo {
+            Some(combo) => Some(Slot {
+                combo,
+                held_since: None,
+                fired: false,
+            }),
+            None => None,
+        };
+        true
+    }
+
+    /// Update the detector with the current button state.
+    ///
+    /// Returns the action to run, if any combo has been just triggered.
+    pub fn update(&mut self, buttons: u8, now: u64) -> Option<Action> {
+        let boot = *self.boot.get_or_insert(now);
+        let mut result = None;
+        for slot in self.slots.iter_mut().flatten() {
+            if buttons != slot.combo.buttons {
+                slot.held_since = None;
+                slot.fired = false;
+                continue;
+            }
+            let since = *slot.held_since.get_or_insert(now);
+            if slot.fired || (slot.combo.at_boot && since != boot) {
+                continue;
+            }
+            if now - since >= u64::from(slot.combo.hold) {
+                slot.fired = true;
+                result = Some(slot.combo.action);
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::ButtonMap;
+
+    /// Pin levels when nothing is pressed: all buttons are pulled up.
+    const AT_REST: u8 = 0b1_1111;
+
+    #[test]
+    fn nothing_fires_at_rest() {
+        let map = ButtonMap::default();
+        let mut d = ComboDetector::new();
+        for now in (0..20_000).step_by(100) {
+            assert!(d.update(map.apply(AT_REST), now).is_none());
+        }
+    }
+
+    #[test]
+    fn hold_menu_to_reboot() {
+        let map = ButtonMap::default();
+        let mut d = ComboDetector::new();
+        let menu = map.apply(AT_REST & !BTN_MENU);
+        assert_eq!(menu, BTN_MENU);
+        assert!(d.update(0, 0).is_none());
+        assert!(d.update(menu, 1000).is_none());
+        assert!(d.update(menu, 5999).is_none());
+        assert!(d.update(menu, 6000) == Some(Action::Reboot));
+        // Fires only once per hold.
+        assert!(d.update(menu, 7000).is_none());
+    }
+
+    #[test]
+    fn recovery_only_at_boot() {
+        let mut d = ComboDetector::new();
+        assert!(d.update(BTN_MENU | BTN_S, 0).is_none());
+        assert!(d.update(BTN_MENU | BTN_S, 1000) == Some(Action::Recovery));
+
+        let mut d = ComboDetector::new();
+        assert!(d.update(0, 0).is_none());
+        assert!(d.update(BTN_MENU | BTN_S, 100).is_none());
+        assert!(d.update(BTN_MENU | BTN_S, 5000).is_none());
+    }
+}
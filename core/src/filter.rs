@@ -0,0 +1,270 @@
+//! Smoothing of touchpad coordinates to reduce jitter.
+//!
+//! All filters are reset when the finger is lifted, so that a new touch
+//! starts exactly where the finger is and doesn't drag behind from the old position.
+//!
+//! The one-euro filter is the best choice for cursors and aiming:
+//! it smooths a lot when the finger is still and almost nothing when it moves fast.
+//! See: <https://gery.casiez.net/1euro/>
+
+/// Max window size for the moving average filter.
+pub const MAX_WINDOW: u8 = 16;
+
+/// The cutoff frequency (in Hz) used by the one-euro filter for the speed.
+const D_CUTOFF: f32 = 1.;
+
+#[derive(Clone, Copy, Default)]
+pub enum FilterConfig {
+    /// Report raw coordinates.
+    #[default]
+    Off,
+    /// The average of the last `window` readings.
+    MovingAverage { window: u8 },
+    /// Exponential smoothing. `alpha` is the weight of the new reading in permille.
+    Exponential { alpha: u16 },
+    /// One-euro filter.
+    ///
+    /// `min_cutoff` is the cutoff frequency at zero speed in centihertz
+    /// (lower is smoother but laggier). `beta` is how fast the cutoff grows
+    /// with speed, in thousandths (higher is less laggy on fast moves).
+    OneEuro { min_cutoff: u16, beta: u16 },
+}
+
+impl FilterConfig {
+    #[must_use]
+    pub const fn is_valid(&self) -> bool {
+        match *self {
+            Self::Off => true,
+            Self::MovingAverage { window } => window != 0 && window <= MAX_WINDOW,
+            Self::Exponential { alpha } => alpha != 0 && alpha <= 1000,
+            Self::OneEuro { min_cutoff, .. } => min_cutoff != 0,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Smoother {
+    config: FilterConfig,
+    /// The ring buffer of the last readings for the moving average.
+    window: [(u16, u16); MAX_WINDOW as usize],
+    /// How many readings are in the window.
+    filled: u8,
+    /// The next position to write in the window.
+    cursor: u8,
+    /// The last output of the exponential or one-euro filter.
+    prev: Option<(f32, f32)>,
+    /// The last filtered speed for the one-euro filter.
+    speed: (f32, f32),
+    /// The time of the last reading in microseconds.
+    prev_time: u64,
+}
+
+impl Smoother {
+    #[must_use]
+    pub const fn config(&self) -> FilterConfig {
+        self.config
+    }
+
+    pub const fn set_config(&mut self, config: FilterConfig) {
+        self.config = config;
+        self.reset();
+    }
+
+    /// Forget all previous readings. Must be called when the finger is lifted.
+    pub const fn reset(&mut self) {
+        self.filled = 0;
+        self.cursor = 0;
+        self.prev = None;
+        self.speed = (0., 0.);
+    }
+
+    /// Filter the new reading taken at the given time (in microseconds).
+    pub fn update(&mut self, x: u16, y: u16, now: u64) -> (u16, u16) {
+        match self.config {
+            FilterConfig::Off => (x, y),
+            FilterConfig::MovingAverage { window } => self.moving_average(x, y, window),
+            FilterConfig::Exponential { alpha } => {
+                let alpha = f32::from(alpha) / 1000.;
+                let (x, y) = (f32::from(x), f32::from(y));
+                let (x, y) = match self.prev {
+                    Some((px, py)) => (px + alpha * (x - px), py + alpha * (y - py)),
+                    None => (x, y),
+                };
+                self.prev = Some((x, y));
+                (to_u16(x), to_u16(y))
+            }
+            FilterConfig::OneEuro { min_cutoff, beta } => {
+                let min_cutoff = f32::from(min_cutoff) / 100.;
+                let beta = f32::from(beta) / 1000.;
+                self.one_euro(x, y, now, min_cutoff, beta)
+            }
+        }
+    }
+
+    fn moving_average(&mut self, x: u16, y: u16, window: u8) -> (u16, u16) {
+        let window = window.clamp(1, MAX_WINDOW);
+        self.window[usize::from(self.cursor)] = (x, y);
+        self.cursor = (self.cursor + 1) % window;
+        self.filled = (self.filled + 1).min(window);
+        let items = &self.window[..usize::from(self.filled)];
+        let sum_x: u32 = items.iter().map(|p| u32::from(p.0)).sum();
+        let sum_y: u32 = items.iter().map(|p| u32::from(p.1)).sum();
+        let n = u32::from(self.filled);
+        #[expect(clippy::cast_possible_truncation)]
+        let avg = ((sum_x / n) as u16, (sum_y / n) as u16);
+        avg
+    }
+
+    fn one_euro(&mut self, x: u16, y: u16, now: u64, min_cutoff: f32, beta: f32) -> (u16, u16) {
+        let (x, y) = (f32::from(x), f32::from(y));
+        let Some((px, py)) = self.prev else {
+            self.prev = Some((x, y));
+            self.prev_time = now;
+            return (to_u16(x), to_u16(y));
+        };
+        #[expect(clippy::cast_precision_loss)]
+        let dt = (now.saturating_sub(self.prev_time) as f32 / 1_000_000.).max(0.000_1);
+        self.prev_time = now;
+
+        // Filter the speed, then use it to pick the cutoff for the position.
+        let a_d = smoothing_factor(dt, D_CUTOFF);
+        let (sx, sy) = self.speed;
+        let sx = sx + a_d * ((x - px) / dt - sx);
+        let sy = sy + a_d * ((y - py) / dt - sy);
+        self.speed = (sx, sy);
+
+        let ax = smoothing_factor(dt, min_cutoff + beta * abs(sx));
+        let ay = smoothing_factor(dt, min_cutoff + beta * abs(sy));
+        let x = px + ax * (x - px);
+        let y = py + ay * (y - py);
+        self.prev = Some((x, y));
+        (to_u16(x), to_u16(y))
+    }
+}
+
+/// The weight of the new value for exponential smoothing with the given cutoff frequency.
+fn smoothing_factor(dt: f32, cutoff: f32) -> f32 {
+    let tau = 1. / (2. * core::f32::consts::PI * cutoff);
+    1. / (1. + tau / dt)
+}
+
+const fn abs(v: f32) -> f32 {
+    if v < 0. {
+        -v
+    } else {
+        v
+    }
+}
+
+#[expect(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn to_u16(v: f32) -> u16 {
+    (v + 0.5) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// A finger held still at (1000, 1000) with ±`amp` jitter.
+    #[expect(clippy::cast_possible_truncation)]
+    fn noisy(n: usize, amp: u16) -> Vec<(u16, u16)> {
+        let mut seed: u32 = 0x1234_5678;
+        (0..n)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                let range = 2 * amp + 1;
+                let dx = (seed as u16) % range;
+                let dy = ((seed >> 16) as u16) % range;
+                (1000 - amp + dx, 1000 - amp + dy)
+            })
+            .collect()
+    }
+
+    /// Run the trace through the filter with readings every 10 ms and return
+    /// the total deviation from the true position after warm-up.
+    fn jitter(config: FilterConfig, trace: &[(u16, u16)]) -> u32 {
+        let mut s = Smoother::default();
+        s.set_config(config);
+        let mut total = 0;
+        for (i, &(x, y)) in trace.iter().enumerate() {
+            let (fx, fy) = s.update(x, y, i as u64 * 10_000);
+            if i >= 20 {
+                total += u32::from(fx.abs_diff(1000) + fy.abs_diff(1000));
+            }
+        }
+        total
+    }
+
+    #[test]
+    fn off_passes_through() {
+        let trace = noisy(100, 20);
+        let mut s = Smoother::default();
+        for &(x, y) in &trace {
+            assert_eq!(s.update(x, y, 0), (x, y));
+        }
+    }
+
+    #[test]
+    fn filters_reduce_noise() {
+        let trace = noisy(200, 20);
+        let raw = jitter(FilterConfig::Off, &trace);
+        let configs = [
+            FilterConfig::MovingAverage { window: 8 },
+            FilterConfig::Exponential { alpha: 200 },
+            FilterConfig::OneEuro {
+                min_cutoff: 100,
+                beta: 1,
+            },
+        ];
+        for config in configs {
+            let filtered = jitter(config, &trace);
+            assert!(filtered * 2 < raw, "{filtered} vs {raw}");
+        }
+    }
+
+    #[test]
+    fn one_euro_follows_fast_moves() {
+        let mut s = Smoother::default();
+        s.set_config(FilterConfig::OneEuro {
+            min_cutoff: 100,
+            beta: 10,
+        });
+        let mut out = (0, 0);
+        for i in 0..20u16 {
+            out = s.update(100 + i * 50, 500, u64::from(i) * 10_000);
+        }
+        // The last input is 1050; the output must not lag far behind.
+        assert!(out.0 > 1000, "lags at {}", out.0);
+        assert_eq!(out.1, 500);
+    }
+
+    #[test]
+    fn reset_starts_at_finger() {
+        let mut s = Smoother::default();
+        s.set_config(FilterConfig::MovingAverage { window: 4 });
+        for _ in 0..4 {
+            s.update(100, 100, 0);
+        }
+        s.reset();
+        assert_eq!(s.update(900, 900, 0), (900, 900));
+    }
+
+    #[test]
+    fn validate() {
+        assert!(FilterConfig::MovingAverage { window: 1 }.is_valid());
+        assert!(!FilterConfig::MovingAverage { window: 0 }.is_valid());
+        assert!(!FilterConfig::MovingAverage {
+            window: MAX_WINDOW + 1
+        }
+        .is_valid());
+        assert!(!FilterConfig::Exponential { alpha: 1001 }.is_valid());
+        assert!(!FilterConfig::OneEuro {
+            min_cutoff: 0,
+            beta: 0
+        }
+        .is_valid());
+    }
+}
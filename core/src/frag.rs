@@ -0,0 +1,338 @@
+//! Fragmentation and reassembly of messages bigger than one ESP-NOW frame.
+//!
+//! Every data frame starts with a kind byte. A message that fits into
+//! a single frame is sent whole. Bigger messages are split into numbered
+//! fragments of the same message ID, and the receiver puts them together,
+//! in any order. Incomplete messages are dropped after a timeout or when
+//! there is not enough memory for newer ones.
+use crate::seq::SEQ_SIZE;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+/// Max size of a data frame: the ESP-NOW payload limit minus the sequence number.
+pub const MAX_FRAME: usize = 250 - SEQ_SIZE;
+/// Max number of fragments in a message.
+pub const MAX_FRAGMENTS: usize = 16;
+/// Max size of message data in a fragment.
+pub const MAX_FRAGMENT: usize = MAX_FRAME - FRAGMENT_HEADER;
+/// Max size of a message.
+pub const MAX_MESSAGE: usize = MAX_FRAGMENT * MAX_FRAGMENTS;
+/// How long (in milliseconds) to wait for missing fragments.
+pub const TIMEOUT: u64 = 2000;
+/// Max number of messages being reassembled at the same time.
+pub const MAX_PARTIAL: usize = 4;
+/// Max number of bytes in all messages being reassembled.
+pub const MAX_BUFFERED: usize = 8 * 1024;
+
+const KIND_WHOLE: u8 = 0;
+const KIND_FRAGMENT: u8 = 1;
+/// Kind, message ID, fragment index, fragments count.
+const FRAGMENT_HEADER: usize = 4;
+
+type Addr = [u8; 6];
+
+/// A parsed data frame.
+pub enum Frame<'a> {
+    Whole(&'a [u8]),
+    Fragment(Fragment<'a>),
+}
+
+pub struct Fragment<'a> {
+    pub id: u8,
+    pub index: u8,
+    pub count: u8,
+    pub data: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    /// Parse a data frame. Returns None if the frame is malformed.
+    #[must_use]
+    pub fn parse(raw: &'a [u8]) -> Option<Self> {
+        let (&kind, rest) = raw.split_first()?;
+        match kind {
+            KIND_WHOLE => Some(Self::Whole(rest)),
+            KIND_FRAGMENT => {
+                let (&[id, index, count], data) = rest.split_first_chunk()?;
+                if index >= count || usize::from(count) > MAX_FRAGMENTS {
+                    return None;
+                }
+                Some(Self::Fragment(Fragment {
+                    id,
+                    index,
+                    count,
+                    data,
+                }))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Split the message into frames. Returns None if the message is too big.
+#[must_use]
+pub fn split(id: u8, data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    if data.len() < MAX_FRAME {
+        let mut frame = Vec::with_capacity(data.len() + 1);
+        frame.push(KIND_WHOLE);
+        frame.extend_from_slice(data);
+        return Some(alloc::vec![frame]);
+    }
+    if data.len() > MAX_MESSAGE {
+        return None;
+    }
+    let chunks = data.chunks(MAX_FRAGMENT);
+    let count = u8::try_from(chunks.len()).ok()?;
+    let mut frames = Vec::with_capacity(chunks.len());
+    for (index, chunk) in (0..count).zip(chunks) {
+        let mut frame = Vec::with_capacity(FRAGMENT_HEADER + chunk.len());
+        frame.extend_from_slice(&[KIND_FRAGMENT, id, index, count]);
+        frame.extend_from_slice(chunk);
+        frames.push(frame);
+    }
+    Some(frames)
+}
+
+/// A message with some of the fragments received.
+struct Partial {
+    addr: Addr,
+    id: u8,
+    /// When the first fragment was received.
+    started: u64,
+    fragments: Vec<Option<Box<[u8]>>>,
+    size: usize,
+}
+
+impl Partial {
+    fn is_complete(&self) -> bool {
+        self.fragments.iter().all(Option::is_some)
+    }
+
+    fn join(self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.size);
+        for fragment in self.fragments.into_iter().flatten() {
+            data.extend_from_slice(&fragment);
+        }
+        data
+    }
+}
+
+#[derive(Default)]
+pub struct Reassembler {
+    partial: Vec<Partial>,
+    /// How many incomplete messages were dropped.
+    pub dropped: u16,
+}
+
+impl Reassembler {
+    /// Add the fragment. Returns the message if it's now complete.
+    pub fn push(&mut self, addr: Addr, fragment: &Fragment<'_>, now: u64) -> Option<Vec<u8>> {
+        self.expire(now);
+        let count = usize::from(fragment.count);
+        let index = usize::from(fragment.index);
+        if fragment.data.len() > MAX_FRAGMENT {
+            return None;
+        }
+        let pos = self
+            .partial
+            .iter()
+            .position(|p| p.addr == addr && p.id == fragment.id && p.fragments.len() == count);
+        let pos = if let Some(pos) = pos {
+            pos
+        } else {
+            // The peer has started a new message with the same ID.
+            self.remove(|p| p.addr == addr && p.id == fragment.id);
+            self.partial.push(Partial {
+                addr,
+                id: fragment.id,
+                started: now,
+                fragments: alloc::vec![None; count],
+                size: 0,
+            });
+            self.partial.len() - 1
+        };
+        let partial = &mut self.partial[pos];
+        if partial.fragments[index].is_none() {
+            partial.size += fragment.data.len();
+            partial.fragments[index] = Some(fragment.data.into());
+        }
+        if partial.is_complete() {
+            return Some(self.partial.swap_remove(pos).join());
+        }
+        self.evict();
+        None
+    }
+
+    /// Drop incomplete messages that are waiting for too long.
+    pub fn expire(&mut self, now: u64) {
+        self.remove(|p| now.saturating_sub(p.started) > TIMEOUT);
+    }
+
+    pub fn clear(&mut self) {
+        self.partial.clear();
+    }
+
+    /// Drop the oldest incomplete messages until the limits are satisfied.
+    fn evict(&mut self) {
+        loop {
+            let buffered: usize = self.partial.iter().map(|p| p.size).sum();
+            if self.partial.len() <= MAX_PARTIAL && buffered <= MAX_BUFFERED {
+                return;
+            }
+            let oldest = self
+                .partial
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, p)| p.started);
+            let Some((pos, _)) = oldest else {
+                return;
+            };
+            self.partial.remove(pos);
+            self.dropped = self.dropped.saturating_add(1);
+        }
+    }
+
+    fn remove(&mut self, f: impl Fn(&Partial) -> bool) {
+        let len = self.partial.len();
+        self.partial.retain(|p| !f(p));
+        let removed = u16::try_from(len - self.partial.len()).unwrap_or(u16::MAX);
+        self.dropped = self.dropped.saturating_add(removed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: Addr = [1; 6];
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len).map(|i| u8::try_from(i % 251).unwrap()).collect()
+    }
+
+    fn push(r: &mut Reassembler, addr: Addr, raw: &[u8], now: u64) -> Option<Vec<u8>> {
+        let Some(Frame::Fragment(fragment)) = Frame::parse(raw) else {
+            panic!("not a fragment");
+        };
+        r.push(addr, &fragment, now)
+    }
+
+    #[test]
+    fn split_and_parse() {
+        let frames = split(0, b"hello").unwrap();
+        assert_eq!(frames.len(), 1);
+        assert!(matches!(
+            Frame::parse(&frames[0]),
+            Some(Frame::Whole(b"hello"))
+        ));
+
+        let frames = split(7, &message(MAX_FRAME)).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert!(frames.iter().all(|f| f.len() <= MAX_FRAME));
+        assert_eq!(
+            split(0, &message(MAX_MESSAGE)).unwrap().len(),
+            MAX_FRAGMENTS
+        );
+        assert!(split(0, &message(MAX_MESSAGE + 1)).is_none());
+
+        assert!(Frame::parse(&[]).is_none());
+        assert!(Frame::parse(&[9]).is_none());
+        assert!(Frame::parse(&[KIND_FRAGMENT, 0, 2, 2]).is_none());
+        assert!(Frame::parse(&[KIND_FRAGMENT, 0, 0, 17]).is_none());
+    }
+
+    #[test]
+    fn reorder() {
+        let msg = message(MAX_FRAGMENT * 3 + 10);
+        let frames = split(1, &msg).unwrap();
+        let mut r = Reassembler::default();
+        assert!(push(&mut r, A, &frames[3], 0).is_none());
+        assert!(push(&mut r, A, &frames[1], 0).is_none());
+        assert!(push(&mut r, A, &frames[0], 0).is_none());
+        assert_eq!(push(&mut r, A, &frames[2], 0), Some(msg));
+        assert_eq!(r.dropped, 0);
+    }
+
+    #[test]
+    fn duplicates() {
+        let msg = message(MAX_FRAGMENT * 2);
+        let frames = split(1, &msg).unwrap();
+        let mut r = Reassembler::default();
+        assert!(push(&mut r, A, &frames[0], 0).is_none());
+        assert!(push(&mut r, A, &frames[0], 1).is_none());
+        assert_eq!(push(&mut r, A, &frames[1], 2), Some(msg));
+        // A late duplicate starts a new message that is never completed.
+        assert!(push(&mut r, A, &frames[1], 3).is_none());
+        r.expire(3 + TIMEOUT + 1);
+        assert_eq!(r.dropped, 1);
+    }
+
+    #[test]
+    fn interleaved_senders() {
+        let (a, b) = (message(MAX_FRAME), message(MAX_FRAGMENT * 2 + 5));
+        let (fa, fb) = (split(3, &a).unwrap(), split(3, &b).unwrap());
+        let mut r = Reassembler::default();
+        assert!(push(&mut r, A, &fa[0], 0).is_none());
+        assert!(push(&mut r, [2; 6], &fb[2], 0).is_none());
+        assert!(push(&mut r, [2; 6], &fb[0], 0).is_none());
+        assert_eq!(push(&mut r, A, &fa[1], 0), Some(a));
+        assert_eq!(push(&mut r, [2; 6], &fb[1], 0), Some(b));
+    }
+
+    #[test]
+    fn loss_and_timeout() {
+        let msg = message(MAX_FRAGMENT * 3);
+        let frames = split(1, &msg).unwrap();
+        let mut r = Reassembler::default();
+        assert!(push(&mut r, A, &frames[0], 0).is_none());
+        assert!(push(&mut r, A, &frames[1], 100).is_none());
+        // The last fragment is lost. It's still in time:
+        r.expire(TIMEOUT);
+        assert_eq!(r.dropped, 0);
+        r.expire(TIMEOUT + 1);
+        assert_eq!(r.dropped, 1);
+        // The retransmitted fragment alone doesn't complete the message.
+        assert!(push(&mut r, A, &frames[2], TIMEOUT + 2).is_none());
+    }
+
+    #[test]
+    fn max_partial() {
+        let frames = split(1, &message(MAX_FRAGMENT * 2)).unwrap();
+        let mut r = Reassembler::default();
+        for i in 0..=MAX_PARTIAL {
+            let addr = [u8::try_from(i).unwrap(); 6];
+            assert!(push(&mut r, addr, &frames[0], i as u64).is_none());
+        }
+        assert_eq!(r.dropped, 1);
+        // The oldest message was evicted, the others can be completed.
+        assert!(push(&mut r, [1; 6], &frames[1], 10).is_some());
+        assert!(push(&mut r, [0; 6], &frames[1], 10).is_none());
+    }
+
+    #[test]
+    fn max_buffered() {
+        // Each message takes more than a third of the buffer.
+        let frames = split(1, &message(MAX_FRAGMENT * MAX_FRAGMENTS)).unwrap();
+        let incomplete = &frames[..MAX_FRAGMENTS - 1];
+        let mut r = Reassembler::default();
+        for (i, addr) in [[1; 6], [2; 6], [3; 6]].into_iter().enumerate() {
+            for frame in incomplete {
+                assert!(push(&mut r, addr, frame, i as u64).is_none());
+            }
+        }
+        assert_eq!(r.dropped, 1);
+        let last = &frames[MAX_FRAGMENTS - 1];
+        assert!(push(&mut r, [1; 6], last, 10).is_none());
+        assert!(push(&mut r, [2; 6], last, 10).is_some());
+        assert!(push(&mut r, [3; 6], last, 10).is_some());
+    }
+
+    #[test]
+    fn oversized_fragment() {
+        let mut raw = alloc::vec![KIND_FRAGMENT, 0, 0, 2];
+        raw.extend_from_slice(&message(MAX_FRAGMENT + 1));
+        let mut r = Reassembler::default();
+        assert!(push(&mut r, A, &raw, 0).is_none());
+        r.clear();
+        assert_eq!(r.dropped, 0);
+    }
+}
@@ -0,0 +1,93 @@
+/// How many reads in a row must fail for the touchpad to be considered down.
+const MAX_FAILURES: u8 = 3;
+/// The delay before the first re-init attempt after the touchpad goes down.
+const MIN_BACKOFF: u64 = 100;
+/// The max delay between re-init attempts.
+const MAX_BACKOFF: u64 = 10_000;
+
+/// Tracks if the touchpad is working and when to try re-initializing it.
+///
+/// All timestamps are in milliseconds.
+#[derive(Clone, Copy)]
+pub struct PadHealth {
+    ready: bool,
+    /// How many reads in a row have failed.
+    failures: u8,
+    /// The current delay between re-init attempts.
+    backoff: u64,
+    /// When the next re-init attempt is allowed.
+    retry_at: u64,
+    /// Total number of failed reads and inits since boot.
+    pub errors: u16,
+    /// Total number of init attempts since boot, including the first one.
+    pub inits: u16,
+}
+
+impl PadHealth {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            ready: false,
+            failures: 0,
+            backoff: MIN_BACKOFF,
+            retry_at: 0,
+            errors: 0,
+            inits: 0,
+        }
+    }
+
+    /// If false, the touchpad must not be read, only buttons are available.
+    #[must_use]
+    pub const fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Check if it's time to try re-initializing the touchpad.
+    #[must_use]
+    pub const fn should_retry(&self, now: u64) -> bool {
+        !self.ready && now >= self.retry_at
+    }
+
+    /// Record the result of initializing the touchpad.
+    pub const fn init_done(&mut self, ok: bool, now: u64) {
+        self.inits = self.inits.saturating_add(1);
+        if ok {
+            self.ready = true;
+            self.failures = 0;
+            self.backoff = MIN_BACKOFF;
+        } else {
+            self.errors = self.errors.saturating_add(1);
+            self.go_down(now);
+        }
+    }
+
+    /// Record a successful read.
+    pub const fn read_ok(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Record a failed read.
+    ///
+    /// Returns true if the touchpad just went down because of too many failures.
+    pub const fn read_failed(&mut self, now: u64) -> bool {
+        self.errors = self.errors.saturating_add(1);
+        self.failures = self.failures.saturating_add(1);
+        if self.ready && self.failures >= MAX_FAILURES {
+            self.backoff = MIN_BACKOFF;
+            self.go_down(now);
+            return true;
+        }
+        false
+    }
+
+    /// Mark the touchpad as not ready and schedule the next re-init attempt.
+    const fn go_down(&mut self, now: u64) {
+        self.ready = false;
+        self.retry_at = now + self.backoff;
+        self.backoff = if self.backoff * 2 > MAX_BACKOFF {
+            MAX_BACKOFF
+        } else {
+            self.backoff * 2
+        };
+    }
+}
@@ -0,0 +1,112 @@
+//! Detection of input inactivity.
+//!
+//! Tracks the time since the last button or touch activity, so that
+//! the main chip can dim the screen or suspend without comparing
+//! every input read. All timestamps are in milliseconds.
+
+pub struct IdleDetector {
+    /// How long without activity before the input is idle. Zero disables detection.
+    timeout: u64,
+    /// When the last activity happened.
+    last_activity: u64,
+    prev_buttons: u8,
+    touched: bool,
+}
+
+impl IdleDetector {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            timeout: 0,
+            last_activity: 0,
+            prev_buttons: 0,
+            touched: false,
+        }
+    }
+
+    /// Set the inactivity timeout in seconds. Zero disables idle detection.
+    pub fn set_timeout(&mut self, timeout: u16, now: u64) {
+        self.timeout = u64::from(timeout) * 1000;
+        self.last_activity = now;
+    }
+
+    #[must_use]
+    pub const fn is_enabled(&self) -> bool {
+        self.timeout != 0
+    }
+
+    /// Record the bitmask of pressed buttons, as returned by [`ButtonMap::apply`].
+    ///
+    /// Holding a button counts as activity, as well as releasing it.
+    ///
+    /// [`ButtonMap::apply`]: crate::layout::ButtonMap::apply
+    pub const fn buttons(&mut self, buttons: u8, now: u64) {
+        if buttons != 0 || buttons != self.prev_buttons {
+            self.last_activity = now;
+        }
+        self.prev_buttons = buttons;
+    }
+
+    /// Record if the touchpad is touched.
+    pub const fn touch(&mut self, touched: bool, now: u64) {
+        if touched || self.touched {
+            self.last_activity = now;
+        }
+        self.touched = touched;
+    }
+
+    /// How long ago the last activity happened.
+    #[must_use]
+    pub const fn idle_for(&self, now: u64) -> u64 {
+        now.saturating_sub(self.last_activity)
+    }
+
+    /// Check if there was no activity for longer than the timeout.
+    #[must_use]
+    pub const fn is_idle(&self, now: u64) -> bool {
+        self.is_enabled() && self.idle_for(now) >= self.timeout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::ButtonMap;
+
+    /// Pin levels when nothing is pressed: all buttons are pulled up.
+    const AT_REST: u8 = 0b1_1111;
+
+    #[test]
+    fn idle_at_rest() {
+        let map = ButtonMap::default();
+        let mut d = IdleDetector::new();
+        d.set_timeout(2, 0);
+        for now in (0..=2000).step_by(100) {
+            d.buttons(map.apply(AT_REST), now);
+            d.touch(false, now);
+        }
+        assert!(d.is_idle(2000));
+        assert_eq!(d.idle_for(2500), 2500);
+    }
+
+    #[test]
+    fn activity() {
+        let map = ButtonMap::default();
+        let mut d = IdleDetector::new();
+        assert!(!d.is_idle(10_000));
+        d.set_timeout(1, 0);
+        // Holding a button.
+        d.buttons(map.apply(AT_REST & !1), 500);
+        d.buttons(map.apply(AT_REST & !1), 1500);
+        assert!(!d.is_idle(1600));
+        // Releasing it.
+        d.buttons(map.apply(AT_REST), 1700);
+        assert!(!d.is_idle(2600));
+        assert!(d.is_idle(2700));
+        // Touching and lifting the finger.
+        d.touch(true, 3000);
+        d.touch(false, 3100);
+        d.touch(false, 3200);
+        assert_eq!(d.idle_for(3500), 400);
+    }
+}
@@ -0,0 +1,135 @@
+//! Extensible input model.
+//!
+//! Besides the five built-in buttons and the touchpad, a board or an accessory
+//! may provide extra buttons and analog axes. The main chip learns what's
+//! available from [`Capabilities`] instead of assuming a fixed layout.
+use crate::sampler::BUTTONS;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+/// Max number of buttons, including the built-in ones.
+pub const MAX_BUTTONS: usize = 32;
+/// Max number of analog axes.
+pub const MAX_AXES: usize = 8;
+/// The version of the extended input format. Bumped on incompatible changes.
+pub const INPUT_FORMAT: u8 = 1;
+
+/// A source of raw analog readings, like an ADC channel.
+///
+/// The firmware implements it for ADC1 pins behind the `adc` feature.
+/// None of the supported boards has analog inputs yet, so by default
+/// they provide no axes and advertise none in [`Capabilities`].
+pub trait AxisSource {
+    /// Read the raw value. Returns None if the reading failed.
+    fn read(&mut self) -> Option<u16>;
+}
+
+/// Maps raw readings of an analog axis into the -1000..=1000 range.
+#[derive(Clone, Copy)]
+pub struct AxisCalibration {
+    pub min: u16,
+    pub center: u16,
+    pub max: u16,
+    /// Readings closer than this to the center are reported as zero.
+    pub dead_zone: u16,
+}
+
+impl Default for AxisCalibration {
+    /// The full range of a 12-bit ADC.
+    fn default() -> Self {
+        Self {
+            min: 0,
+            center: 2048,
+            max: 4095,
+            dead_zone: 64,
+        }
+    }
+}
+
+impl AxisCalibration {
+    #[must_use]
+    pub const fn is_valid(&self) -> bool {
+        self.min < self.center && self.center < self.max
+    }
+
+    #[must_use]
+    pub fn normalize(&self, raw: u16) -> i16 {
+        let offset = i32::from(raw) - i32::from(self.center);
+        let dead_zone = i32::from(self.dead_zone);
+        let range = if offset > 0 {
+            i32::from(self.max) - i32::from(self.center)
+        } else {
+            i32::from(self.center) - i32::from(self.min)
+        };
+        let range = range - dead_zone;
+        let distance = offset.abs() - dead_zone;
+        if distance <= 0 || range <= 0 {
+            return 0;
+        }
+        let value = (distance * 1000 / range).min(1000);
+        let value = i16::try_from(value).unwrap_or_default();
+        if offset < 0 {
+            -value
+        } else {
+            value
+        }
+    }
+}
+
+pub struct Axis<'a> {
+    source: Box<dyn AxisSource + 'a>,
+    pub calibration: AxisCalibration,
+}
+
+impl<'a> Axis<'a> {
+    #[must_use]
+    pub fn new(source: impl AxisSource + 'a) -> Self {
+        Self {
+            source: Box::new(source),
+            calibration: AxisCalibration::default(),
+        }
+    }
+
+    /// Read the calibrated value. Failed readings are reported as the center.
+    pub fn read(&mut self) -> i16 {
+        self.source
+            .read()
+            .map_or(0, |raw| self.calibration.normalize(raw))
+    }
+}
+
+/// What input hardware is available.
+#[derive(Clone, Copy)]
+pub struct Capabilities {
+    /// The number of buttons, including the built-in ones.
+    pub buttons: u8,
+    /// The number of analog axes.
+    pub axes: u8,
+    /// If the touchpad is connected and working.
+    pub touchpad: bool,
+}
+
+impl Capabilities {
+    #[must_use]
+    pub fn new(extra_buttons: usize, axes: usize, touchpad: bool) -> Self {
+        let buttons = (BUTTONS + extra_buttons).min(MAX_BUTTONS);
+        Self {
+            buttons: u8::try_from(buttons).unwrap_or_default(),
+            axes: u8::try_from(axes.min(MAX_AXES)).unwrap_or_default(),
+            touchpad,
+        }
+    }
+}
+
+/// The state of all inputs described by [`Capabilities`].
+pub struct InputState {
+    /// The number of buttons, including the built-in ones.
+    pub count: u8,
+    /// Bitmask of pressed buttons. The built-in buttons go first.
+    pub buttons: u32,
+    /// Calibrated values of analog axes.
+    pub axes: Vec<i16>,
+    pub pos: Option<(u16, u16)>,
+    /// How long ago (in microseconds) the latest input edge was captured.
+    pub edge_age: u32,
+}
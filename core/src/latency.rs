@@ -0,0 +1,167 @@
+//! Input latency instrumentation.
+//!
+//! Tracks when input was captured and, in the measurement mode, collects
+//! the histogram of delays between an input edge (a button pressed or released,
+//! a touch started or ended) being captured and the main chip reading it.
+//! All timestamps are in microseconds.
+//!
+//! Edges are tracked on every tick, even when background sampling is disabled.
+//! When sampling is disabled, touch edges are seen only as often as the touchpad
+//! is polled for idle detection.
+//!
+//! The `ReadFrame` response reports the sample and edge ages, and the `InputExt`
+//! response reports the edge age. The standard `ReadInput` response has no field
+//! for either. Direct reads capture the input while handling the request,
+//! so their sample age is always zero.
+
+/// Number of buckets in the latency histogram.
+pub const BUCKETS: usize = 16;
+/// The width of a histogram bucket in microseconds.
+///
+/// The last bucket also includes everything above the histogram range.
+pub const BUCKET_WIDTH: u64 = 1000;
+
+#[derive(Clone, Copy, Default)]
+pub struct LatencyStats {
+    /// How many edges have been measured.
+    pub count: u16,
+    pub min: u32,
+    pub max: u32,
+    pub mean: u32,
+    pub histogram: [u16; BUCKETS],
+}
+
+#[derive(Default)]
+pub struct Latency {
+    /// If the latency histogram is collected.
+    measuring: bool,
+    prev_buttons: u8,
+    prev_touched: bool,
+    /// When the latest input sample was captured.
+    last_sample: u64,
+    /// When the latest input edge was captured.
+    last_edge: u64,
+    /// When the earliest input edge not yet read by the main chip was captured.
+    pending_edge: Option<u64>,
+    stats: LatencyStats,
+    sum: u64,
+}
+
+impl Latency {
+    /// Start or stop the measurement mode. Starting resets the stats.
+    pub fn set_measuring(&mut self, measuring: bool) {
+        self.measuring = measuring;
+        self.pending_edge = None;
+        self.stats = LatencyStats::default();
+        self.sum = 0;
+    }
+
+    #[must_use]
+    pub const fn stats(&self) -> LatencyStats {
+        self.stats
+    }
+
+    /// Record that the input sample for the main chip has been captured.
+    pub const fn capture(&mut self, now: u64) {
+        self.last_sample = now;
+    }
+
+    /// Record the current state of buttons.
+    pub const fn buttons(&mut self, buttons: u8, now: u64) {
+        if buttons != self.prev_buttons {
+            self.prev_buttons = buttons;
+            self.edge(now);
+        }
+    }
+
+    /// Record if the touchpad is currently touched.
+    pub const fn touch(&mut self, touched: bool, now: u64) {
+        if touched != self.prev_touched {
+            self.prev_touched = touched;
+            self.edge(now);
+        }
+    }
+
+    const fn edge(&mut self, now: u64) {
+        self.last_edge = now;
+        if self.pending_edge.is_none() {
+            self.pending_edge = Some(now);
+        }
+    }
+
+    /// How long ago the latest input sample and the latest input edge were captured.
+    #[must_use]
+    pub fn ages(&self, now: u64) -> (u32, u32) {
+        let age = now.saturating_sub(self.last_sample);
+        let edge_age = now.saturating_sub(self.last_edge);
+        (saturate(age), saturate(edge_age))
+    }
+
+    /// Record that the input has been read by the main chip.
+    pub fn report(&mut self, now: u64) {
+        let Some(edge) = self.pending_edge.take() else {
+            return;
+        };
+        if !self.measuring {
+            return;
+        }
+        let delay = now.saturating_sub(edge);
+        let bucket = usize::try_from(delay / BUCKET_WIDTH).unwrap_or(BUCKETS);
+        let bucket = bucket.min(BUCKETS - 1);
+        let s = &mut self.stats;
+        s.histogram[bucket] = s.histogram[bucket].saturating_add(1);
+        let delay32 = saturate(delay);
+        s.min = if s.count == 0 {
+            delay32
+        } else {
+            s.min.min(delay32)
+        };
+        s.max = s.max.max(delay32);
+        s.count = s.count.saturating_add(1);
+        self.sum = self.sum.saturating_add(delay);
+        s.mean = saturate(self.sum / u64::from(s.count));
+    }
+}
+
+fn saturate(v: u64) -> u32 {
+    u32::try_from(v).unwrap_or(u32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edges_without_reads() {
+        let mut l = Latency::default();
+        l.set_measuring(true);
+        l.buttons(0, 100);
+        l.buttons(1, 1_000);
+        // Later edges don't move the pending one.
+        l.buttons(0, 2_000);
+        l.report(3_500);
+        l.touch(true, 10_000);
+        l.touch(true, 11_000);
+        l.report(30_000);
+        // Nothing has changed since the last read.
+        l.report(40_000);
+        let s = l.stats();
+        assert_eq!(s.count, 2);
+        assert_eq!(s.min, 2_500);
+        assert_eq!(s.max, 20_000);
+        assert_eq!(s.mean, 11_250);
+        assert_eq!(s.histogram[2], 1);
+        assert_eq!(s.histogram[BUCKETS - 1], 1);
+    }
+
+    #[test]
+    fn ages() {
+        let mut l = Latency::default();
+        l.touch(true, 1_000);
+        l.capture(5_000);
+        assert_eq!(l.ages(6_000), (1_000, 5_000));
+        // Not measuring: the stats stay empty.
+        l.report(7_000);
+        assert_eq!(l.stats().count, 0);
+    }
+}
@@ -0,0 +1,124 @@
+//! Mapping of physical button pins to the logical buttons reported to the main chip.
+//!
+//! Physical pins are indexed in the same order as logical buttons
+//! in the default layout: S, E, W, N, menu.
+use crate::sampler::BUTTONS;
+
+/// The source value for a logical button that isn't mapped to any pin.
+pub const UNMAPPED: u8 = 0xFF;
+
+/// Bitmask of all physical pins.
+///
+/// The buttons are pulled up, so all of them are active low by default.
+const ALL_PINS: u8 = (1 << BUTTONS) - 1;
+
+#[derive(Clone, Copy)]
+pub struct ButtonMap {
+    /// For each logical button, the index of the physical pin it's read from.
+    pub sources: [u8; BUTTONS],
+    /// Bitmask of physical pins that are pressed when the level is low.
+    pub active_low: u8,
+}
+
+impl Default for ButtonMap {
+    fn default() -> Self {
+        Self {
+            sources: [0, 1, 2, 3, 4],
+            active_low: ALL_PINS,
+        }
+    }
+}
+
+impl ButtonMap {
+    pub const SIZE: usize = BUTTONS + 1;
+
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        let max = u8::try_from(BUTTONS).unwrap_or_default();
+        self.sources.iter().all(|&s| s < max || s == UNMAPPED)
+    }
+
+    /// Convert the bitmask of physical pins that are high
+    /// into the bitmask of pressed logical buttons.
+    #[must_use]
+    pub fn apply(&self, levels: u8) -> u8 {
+        let pressed = levels ^ self.active_low;
+        let mut buttons = 0;
+        for (i, &source) in self.sources.iter().enumerate() {
+            if source != UNMAPPED && pressed & (1 << source) != 0 {
+                buttons |= 1 << i;
+            }
+        }
+        buttons
+    }
+
+    /// Convert the bitmask of pressed logical buttons into the format
+    /// of the standard input response.
+    ///
+    /// The main chip expects the raw pin levels of the default layout,
+    /// where a set bit means that the button is released.
+    #[must_use]
+    pub const fn to_levels(buttons: u8) -> u8 {
+        !buttons & ALL_PINS
+    }
+
+    #[must_use]
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut raw = [0u8; Self::SIZE];
+        raw[..BUTTONS].copy_from_slice(&self.sources);
+        raw[BUTTONS] = self.active_low;
+        raw
+    }
+
+    #[must_use]
+    pub fn from_bytes(raw: [u8; Self::SIZE]) -> Option<Self> {
+        let (sources, [active_low]) = raw.split_at(BUTTONS) else {
+            return None;
+        };
+        let map = Self {
+            sources: sources.try_into().ok()?,
+            active_low: *active_low,
+        };
+        if !map.is_valid() {
+            return None;
+        }
+        Some(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_is_active_low() {
+        let map = ButtonMap::default();
+        // All pins are pulled up when nothing is pressed.
+        assert_eq!(map.apply(0b1_1111), 0);
+        // S and menu are pressed.
+        assert_eq!(map.apply(0b0_1110), 0b1_0001);
+        assert_eq!(ButtonMap::to_levels(0b1_0001), 0b0_1110);
+    }
+
+    #[test]
+    fn remap() {
+        let map = ButtonMap {
+            sources: [1, 0, UNMAPPED, 3, 4],
+            active_low: 0b1_1110,
+        };
+        // Pin 0 is active high, pin 1 is active low.
+        assert_eq!(map.apply(0b1_1111), 0b10);
+        assert_eq!(map.apply(0b1_1100), 0b01);
+        // An unmapped button is never pressed.
+        assert_eq!(map.apply(0b1_1011), 0b10);
+    }
+
+    #[test]
+    fn bytes_roundtrip() {
+        let map = ButtonMap::default();
+        let map = ButtonMap::from_bytes(map.to_bytes()).unwrap();
+        assert_eq!(map.sources, [0, 1, 2, 3, 4]);
+        assert_eq!(map.active_low, 0b1_1111);
+        assert!(ButtonMap::from_bytes([5, 0, 0, 0, 0, 0]).is_none());
+    }
+}
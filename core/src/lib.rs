@@ -0,0 +1,43 @@
+//! Hardware-independent logic of the firmware.
+//!
+//! Everything here builds for the host, so it can be tested
+//! without the device using `task test`.
+#![cfg_attr(not(test), no_std)]
+#![deny(clippy::all, clippy::pedantic, clippy::nursery)]
+#![allow(
+    clippy::missing_errors_doc,
+    clippy::missing_panics_doc,
+    clippy::needless_pass_by_value,
+    clippy::new_without_default,
+    clippy::wildcard_imports
+)]
+// `f32::mul_add` is only available with std.
+#![cfg_attr(test, allow(clippy::suboptimal_flops))]
+extern crate alloc;
+
+pub mod backoff;
+pub mod beacon;
+pub mod channel;
+pub mod combo;
+pub mod filter;
+pub mod frag;
+pub mod health;
+pub mod idle;
+pub mod input;
+pub mod latency;
+pub mod layout;
+pub mod outbox;
+pub mod pairing;
+pub mod peers;
+pub mod pinnacle;
+pub mod power;
+pub mod proto;
+pub mod replay;
+pub mod ring;
+pub mod sampler;
+pub mod seq;
+#[cfg(test)]
+mod sim;
+pub mod stick;
+pub mod stream;
+pub mod touch;
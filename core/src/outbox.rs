@@ -0,0 +1,594 @@
+//! Queues of outgoing ESP-NOW frames with retries and delivery statuses.
+//!
+//! The queues don't talk to esp-now themselves: the firmware picks the frames
+//! to send and reports back acks and naks. All memory is allocated upfront,
+//! so the outbox can be updated from the esp-now callback.
+use crate::backoff::RetryPolicy;
+use crate::frag;
+use crate::peers::MAX_PEERS;
+use crate::ring::{Pool, Ring};
+use firefly_types::spi::SendStatus;
+
+/// Max number of reliable frames waiting for a single peer.
+///
+/// It's enough to fit a message split into the max number of fragments.
+pub const QUEUE_SIZE: usize = frag::MAX_FRAGMENTS;
+/// Max number of unreliable and latest-wins frames waiting for a single peer.
+const FAST_SIZE: usize = 8;
+/// Max number of peers with queued frames: all ESP-NOW peers and the broadcast address.
+const MAX_QUEUES: usize = MAX_PEERS + 1;
+/// Max number of frames waiting to be sent to all peers together.
+const POOL_SIZE: usize = 64;
+/// Max size of an ESP-NOW frame.
+pub const MAX_DATA: usize = 250;
+/// How many recent messages to remember the delivery status for.
+const HISTORY_SIZE: usize = 32;
+
+type Addr = [u8; 6];
+
+/// How hard to try delivering a message.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    /// Sent once, never retried.
+    Unreliable,
+    /// Retried according to the retry policy, but dropped as soon as
+    /// a newer message of the same class is queued for the same peer.
+    Latest,
+    /// Retried according to the retry policy and delivered in order.
+    Reliable,
+}
+
+impl TryFrom<u8> for Class {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        let class = match value {
+            0 => Self::Unreliable,
+            1 => Self::Latest,
+            2 => Self::Reliable,
+            _ => return Err(()),
+        };
+        Ok(class)
+    }
+}
+
+/// There is no room for another message: the queue for the peer is full,
+/// there are too many peers with queued messages, or the packet pool is exhausted.
+pub struct QueueFull;
+
+/// Ack or nak reported by esp-now.
+pub struct Event {
+    pub addr: Addr,
+    pub is_ok: bool,
+}
+
+struct Msg {
+    id: u16,
+    class: Class,
+    /// The index of the buffer in the packet pool holding the message data.
+    packet: usize,
+    attempts: u8,
+    /// When the message was queued.
+    queued_at: u64,
+    /// The message must not be sent before this time.
+    retry_at: u64,
+}
+
+/// Outgoing messages for a single peer.
+///
+/// Unreliable and latest-wins messages go before reliable ones,
+/// so that they are not blocked by retries of reliable messages.
+struct Queue {
+    addr: Addr,
+    /// The message waiting for ack/nak from esp-now.
+    sending: Option<Msg>,
+    fast: Ring<Msg, FAST_SIZE>,
+    reliable: Ring<Msg, QUEUE_SIZE>,
+}
+
+impl Queue {
+    fn len(&self) -> usize {
+        usize::from(self.sending.is_some()) + self.fast.len() + self.reliable.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Take the next message that is not waiting for the retry delay.
+    ///
+    /// Reliable messages are taken only in order.
+    fn take_ready(&mut self, now: u64) -> Option<Msg> {
+        let maybe_pos = self.fast.iter().position(|msg| msg.retry_at <= now);
+        if let Some(pos) = maybe_pos {
+            return self.fast.remove(pos);
+        }
+        if self.reliable.front().is_some_and(|msg| msg.retry_at <= now) {
+            return self.reliable.pop_front();
+        }
+        None
+    }
+}
+
+struct State {
+    addr: Addr,
+    status: SendStatus,
+}
+
+struct Record {
+    id: u16,
+    status: SendStatus,
+}
+
+/// Delivery statuses of the latest message for each peer and of recent messages.
+struct Statuses {
+    states: Ring<State, MAX_QUEUES>,
+    history: Ring<Record, HISTORY_SIZE>,
+}
+
+impl Statuses {
+    /// Store the delivery status of the message and make it the latest status for the peer.
+    fn set(&mut self, addr: Addr, id: u16, status: SendStatus) {
+        self.set_peer(addr, status);
+        self.history.retain(|record| record.id != id);
+        if self.history.is_full() {
+            self.history.pop_front();
+        }
+        _ = self.history.push_back(Record { id, status });
+    }
+
+    /// Store the delivery status of the latest message for the peer.
+    ///
+    /// If there are too many peers, the status of the least recently updated one is forgotten.
+    fn set_peer(&mut self, addr: Addr, status: SendStatus) {
+        self.states.retain(|state| state.addr != addr);
+        if self.states.is_full() {
+            self.states.pop_front();
+        }
+        _ = self.states.push_back(State { addr, status });
+    }
+}
+
+type Packets = Pool<POOL_SIZE, MAX_DATA>;
+
+pub struct Outbox {
+    queues: Ring<Queue, MAX_QUEUES>,
+    statuses: Statuses,
+    /// There is at most one frame in flight for each peer, so it's enough
+    /// to fit an event for every peer.
+    events: Ring<Event, MAX_QUEUES>,
+    packets: Packets,
+    next_id: u16,
+    pub policy: RetryPolicy,
+}
+
+impl Outbox {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            queues: Ring::new(),
+            statuses: Statuses {
+                states: Ring::new(),
+                history: Ring::new(),
+            },
+            events: Ring::new(),
+            packets: Packets::new(),
+            next_id: 0,
+            policy: RetryPolicy::DEFAULT,
+        }
+    }
+
+    /// Drop pending messages and events, and reset the retry policy.
+    ///
+    /// Delivery statuses are kept.
+    pub fn clear(&mut self) {
+        self.queues.clear();
+        self.events.clear();
+        self.packets.clear();
+        self.policy = RetryPolicy::DEFAULT;
+    }
+
+    /// Queue a message to be sent and return its ID.
+    ///
+    /// If there is no room for the message, it is rejected
+    /// and the latest status for the peer is set to failed.
+    pub fn enqueue(
+        &mut self,
+        addr: Addr,
+        class: Class,
+        data: &[u8],
+        now: u64,
+    ) -> Result<u16, QueueFull> {
+        let result = self.try_enqueue(addr, class, data, now);
+        if result.is_err() {
+            self.statuses.set_peer(addr, SendStatus::Failed);
+        }
+        result
+    }
+
+    fn try_enqueue(
+        &mut self,
+        addr: Addr,
+        class: Class,
+        data: &[u8],
+        now: u64,
+    ) -> Result<u16, QueueFull> {
+        let Self {
+            queues,
+            statuses,
+            packets,
+            ..
+        } = self;
+        if !queues.iter().any(|q| q.addr == addr) {
+            let queue = Queue {
+                addr,
+                sending: None,
+                fast: Ring::new(),
+                reliable: Ring::new(),
+            };
+            if queues.push_back(queue).is_err() {
+                return Err(QueueFull);
+            }
+        }
+        let Some(queue) = queues.iter_mut().find(|q| q.addr == addr) else {
+            unreachable!("the queue is just added");
+        };
+        if class == Class::Latest {
+            // The new message makes the older ones obsolete.
+            queue.fast.retain(|msg| {
+                if msg.class == Class::Latest {
+                    fail(statuses, packets, addr, msg);
+                }
+                msg.class != Class::Latest
+            });
+        }
+        let is_full = match class {
+            Class::Reliable => queue.reliable.is_full(),
+            Class::Latest | Class::Unreliable => queue.fast.is_full(),
+        };
+        if is_full {
+            return Err(QueueFull);
+        }
+        let Some(packet) = packets.alloc(data) else {
+            return Err(QueueFull);
+        };
+        let id = self.next_id;
+        self.next_id = id.wrapping_add(1);
+        let msg = Msg {
+            id,
+            class,
+            packet,
+            attempts: 0,
+            queued_at: now,
+            retry_at: now,
+        };
+        // Never fails: checked above that there is room.
+        _ = match class {
+            Class::Reliable => queue.reliable.push_back(msg),
+            Class::Latest | Class::Unreliable => queue.fast.push_back(msg),
+        };
+        statuses.set(addr, id, SendStatus::Sending(0));
+        Ok(id)
+    }
+
+    /// How many peers have queued messages.
+    #[must_use]
+    pub const fn peers(&self) -> usize {
+        self.queues.len()
+    }
+
+    /// Forget peers that have nothing to send.
+    pub fn remove_idle(&mut self) {
+        self.queues.retain(|q| !q.is_empty());
+    }
+
+    /// Mark the next ready message for the peer with the given index
+    /// as being sent and copy it into the buffer.
+    ///
+    /// Returns the peer address, the message ID, and the message size.
+    /// Messages that are past the deadline are marked as failed and dropped.
+    pub fn start_sending(
+        &mut self,
+        index: usize,
+        now: u64,
+        buf: &mut [u8; MAX_DATA],
+    ) -> Option<(Addr, u16, usize)> {
+        let queue = self.queues.get_mut(index)?;
+        while queue.sending.is_none() {
+            let msg = queue.take_ready(now)?;
+            if self.policy.expired(msg.queued_at, now) {
+                fail(&mut self.statuses, &mut self.packets, queue.addr, &msg);
+                continue;
+            }
+            let status = SendStatus::Sending(msg.attempts);
+            self.statuses.set(queue.addr, msg.id, status);
+            let data = self.packets.get(msg.packet);
+            buf[..data.len()].copy_from_slice(data);
+            let picked = (queue.addr, msg.id, data.len());
+            queue.sending = Some(msg);
+            return Some(picked);
+        }
+        None
+    }
+
+    /// Drop the message that esp-now refused to send.
+    pub fn on_refused(&mut self, addr: Addr, id: u16) {
+        let Some(queue) = self.queues.iter_mut().find(|q| q.addr == addr) else {
+            return;
+        };
+        if let Some(msg) = queue.sending.take_if(|msg| msg.id == id) {
+            fail(&mut self.statuses, &mut self.packets, addr, &msg);
+        }
+    }
+
+    /// Handle ack/nak of the message being sent to the peer.
+    ///
+    /// A message that is not acknowledged is scheduled for a retry after a delay.
+    /// The `random` value is used for the delay jitter.
+    pub fn on_sent(&mut self, addr: Addr, is_ok: bool, now: u64, random: u32) {
+        let Some(queue) = self.queues.iter_mut().find(|q| q.addr == addr) else {
+            return;
+        };
+        let Some(mut msg) = queue.sending.take() else {
+            return;
+        };
+        if is_ok {
+            let status = SendStatus::Delivered(msg.attempts);
+            self.statuses.set(addr, msg.id, status);
+            self.packets.free(msg.packet);
+            return;
+        }
+        msg.attempts = msg.attempts.saturating_add(1);
+        let policy = self.policy;
+        let give_up = msg.attempts >= policy.max_attempts || policy.expired(msg.queued_at, now);
+        let superseded = queue.fast.iter().any(|m| m.class == Class::Latest);
+        msg.retry_at = now + policy.delay(msg.attempts, random);
+        let (id, attempts) = (msg.id, msg.attempts);
+        // The lane might be already filled up with newer messages.
+        let retry = match msg.class {
+            Class::Reliable if !give_up => queue.reliable.push_front(msg),
+            Class::Latest if !give_up && !superseded => queue.fast.push_back(msg),
+            _ => Err(msg),
+        };
+        match retry {
+            Ok(()) => self.statuses.set(addr, id, SendStatus::Sending(attempts)),
+            Err(msg) => fail(&mut self.statuses, &mut self.packets, addr, &msg),
+        }
+    }
+
+    /// Record the event reported by esp-now to be handled later.
+    ///
+    /// There is at most one frame in flight for each queue, so the events
+    /// should always fit. But if esp-now reports a frame that was not sent
+    /// from the outbox, an event could be lost and the peer would wait for it forever.
+    /// So, if there is no room, the event is handled right away.
+    pub fn push_event(&mut self, event: Event, now: u64, random: u32) {
+        if let Err(event) = self.events.push_back(event) {
+            self.on_sent(event.addr, event.is_ok, now, random);
+        }
+    }
+
+    /// Take the oldest event recorded by [`Outbox::push_event`].
+    pub const fn pop_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// How many reliable messages for the peer are waiting to be sent.
+    #[must_use]
+    pub fn queued(&self, addr: Addr) -> usize {
+        let maybe_queue = self.queues.iter().find(|q| q.addr == addr);
+        maybe_queue.map_or(0, |q| q.reliable.len())
+    }
+
+    /// How many more messages can be queued for all peers together.
+    #[must_use]
+    pub fn available(&self) -> usize {
+        self.packets.available()
+    }
+
+    /// Get the delivery state of the latest message for the given peer.
+    #[must_use]
+    pub fn status(&self, addr: Addr) -> SendStatus {
+        let states = &self.statuses.states;
+        let maybe_state = states.iter().find(|state| state.addr == addr);
+        maybe_state.map_or(SendStatus::Empty, |state| state.status)
+    }
+
+    /// Get the delivery state of the message with the given ID.
+    ///
+    /// Only the most recent messages are remembered. For older ones,
+    /// the status is [`SendStatus::Empty`].
+    #[must_use]
+    pub fn message_status(&self, id: u16) -> SendStatus {
+        let history = &self.statuses.history;
+        let maybe_record = history.iter().find(|record| record.id == id);
+        maybe_record.map_or(SendStatus::Empty, |record| record.status)
+    }
+}
+
+/// Mark the message as failed and free its packet buffer.
+fn fail(statuses: &mut Statuses, packets: &mut Packets, addr: Addr, msg: &Msg) {
+    statuses.set(addr, msg.id, SendStatus::Failed);
+    packets.free(msg.packet);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(i: usize) -> Addr {
+        [u8::try_from(i).unwrap(); 6]
+    }
+
+    #[test]
+    fn reliable_lane_full() {
+        let mut outbox = Outbox::new();
+        for _ in 0..QUEUE_SIZE {
+            assert!(outbox.enqueue(addr(1), Class::Reliable, b"hi", 0).is_ok());
+        }
+        assert!(outbox.enqueue(addr(1), Class::Reliable, b"hi", 0).is_err());
+        assert_eq!(outbox.status(addr(1)), SendStatus::Failed);
+        // The other lane and other peers are not affected.
+        assert!(outbox.enqueue(addr(1), Class::Unreliable, b"hi", 0).is_ok());
+        assert!(outbox.enqueue(addr(2), Class::Reliable, b"hi", 0).is_ok());
+    }
+
+    #[test]
+    fn fast_lane_full() {
+        let mut outbox = Outbox::new();
+        for _ in 0..FAST_SIZE {
+            assert!(outbox.enqueue(addr(1), Class::Unreliable, b"hi", 0).is_ok());
+        }
+        assert!(outbox
+            .enqueue(addr(1), Class::Unreliable, b"hi", 0)
+            .is_err());
+        // There are no older latest-wins messages to replace.
+        assert!(outbox.enqueue(addr(1), Class::Latest, b"hi", 0).is_err());
+        assert!(outbox.enqueue(addr(1), Class::Reliable, b"hi", 0).is_ok());
+    }
+
+    #[test]
+    fn latest_replaces_older() {
+        let mut outbox = Outbox::new();
+        let first = outbox
+            .enqueue(addr(1), Class::Latest, b"hi", 0)
+            .ok()
+            .unwrap();
+        for _ in 0..FAST_SIZE * 2 {
+            assert!(outbox.enqueue(addr(1), Class::Latest, b"hi", 0).is_ok());
+        }
+        assert_eq!(outbox.queues.front().unwrap().fast.len(), 1);
+        assert_eq!(outbox.available(), POOL_SIZE - 1);
+        assert_eq!(outbox.message_status(first), SendStatus::Failed);
+    }
+
+    #[test]
+    fn too_many_queues() {
+        let mut outbox = Outbox::new();
+        for i in 0..MAX_QUEUES {
+            assert!(outbox.enqueue(addr(i), Class::Unreliable, b"hi", 0).is_ok());
+        }
+        let last = addr(MAX_QUEUES);
+        assert!(outbox.enqueue(last, Class::Unreliable, b"hi", 0).is_err());
+        assert_eq!(outbox.status(last), SendStatus::Failed);
+        // Peers that already have a queue can still use it.
+        assert!(outbox.enqueue(addr(0), Class::Unreliable, b"hi", 0).is_ok());
+    }
+
+    #[test]
+    fn pool_exhausted() {
+        let mut outbox = Outbox::new();
+        for i in 0..POOL_SIZE {
+            let peer = addr(i % MAX_QUEUES);
+            assert!(outbox.enqueue(peer, Class::Reliable, b"hi", 0).is_ok());
+        }
+        assert_eq!(outbox.available(), 0);
+        assert!(outbox
+            .enqueue(addr(0), Class::Unreliable, b"hi", 0)
+            .is_err());
+        // Rejected messages don't leak buffers.
+        let total: usize = outbox.queues.iter().map(Queue::len).sum();
+        assert_eq!(total, POOL_SIZE);
+    }
+
+    #[test]
+    fn oversized_message() {
+        let mut outbox = Outbox::new();
+        let data = [0; MAX_DATA + 1];
+        assert!(outbox.enqueue(addr(1), Class::Reliable, &data, 0).is_err());
+        let data = &data[..MAX_DATA];
+        assert!(outbox.enqueue(addr(1), Class::Reliable, data, 0).is_ok());
+    }
+
+    #[test]
+    fn retry_until_delivered() {
+        let mut outbox = Outbox::new();
+        let mut buf = [0; MAX_DATA];
+        let id = outbox
+            .enqueue(addr(1), Class::Reliable, b"hi", 0)
+            .ok()
+            .unwrap();
+        let picked = outbox.start_sending(0, 0, &mut buf);
+        assert_eq!(picked, Some((addr(1), id, 2)));
+        assert_eq!(&buf[..2], b"hi");
+        // Only one message in flight.
+        assert!(outbox.start_sending(0, 0, &mut buf).is_none());
+
+        outbox.on_sent(addr(1), false, 0, 0);
+        assert_eq!(outbox.message_status(id), SendStatus::Sending(1));
+        // Waiting for the retry delay.
+        assert!(outbox.start_sending(0, 0, &mut buf).is_none());
+        let later = outbox.policy.delay(1, 0);
+        assert!(outbox.start_sending(0, later, &mut buf).is_some());
+        outbox.on_sent(addr(1), true, later, 0);
+        assert_eq!(outbox.message_status(id), SendStatus::Delivered(1));
+        assert_eq!(outbox.available(), POOL_SIZE);
+        outbox.remove_idle();
+        assert_eq!(outbox.peers(), 0);
+    }
+
+    #[test]
+    fn refused() {
+        let mut outbox = Outbox::new();
+        let mut buf = [0; MAX_DATA];
+        let id = outbox
+            .enqueue(addr(1), Class::Reliable, b"hi", 0)
+            .ok()
+            .unwrap();
+        assert!(outbox.start_sending(0, 0, &mut buf).is_some());
+        outbox.on_refused(addr(1), id);
+        assert_eq!(outbox.message_status(id), SendStatus::Failed);
+        assert_eq!(outbox.available(), POOL_SIZE);
+    }
+
+    #[test]
+    fn events_overflow() {
+        let mut outbox = Outbox::new();
+        let id = outbox
+            .enqueue(addr(1), Class::Reliable, b"hi", 0)
+            .ok()
+            .unwrap();
+        let mut buf = [0; MAX_DATA];
+        assert!(outbox.start_sending(0, 0, &mut buf).is_some());
+        // Events for frames that were not sent from the outbox.
+        for i in 0..MAX_QUEUES {
+            let event = Event {
+                addr: addr(100 + i),
+                is_ok: true,
+            };
+            outbox.push_event(event, 0, 0);
+        }
+        let event = Event {
+            addr: addr(1),
+            is_ok: true,
+        };
+        outbox.push_event(event, 0, 0);
+        // The event that didn't fit is not lost.
+        assert!(outbox.queues.front().unwrap().sending.is_none());
+        assert_eq!(outbox.message_status(id), SendStatus::Delivered(0));
+        let mut events = 0;
+        while outbox.pop_event().is_some() {
+            events += 1;
+        }
+        assert_eq!(events, MAX_QUEUES);
+    }
+
+    #[test]
+    fn clear() {
+        let mut outbox = Outbox::new();
+        let id = outbox
+            .enqueue(addr(1), Class::Reliable, b"hi", 0)
+            .ok()
+            .unwrap();
+        outbox.policy.max_attempts = 1;
+        outbox.clear();
+        assert_eq!(outbox.peers(), 0);
+        assert_eq!(outbox.available(), POOL_SIZE);
+        assert_eq!(
+            outbox.policy.max_attempts,
+            RetryPolicy::DEFAULT.max_attempts
+        );
+        // Statuses are kept.
+        assert_eq!(outbox.message_status(id), SendStatus::Sending(0));
+    }
+}
@@ -0,0 +1,582 @@
+//! Key exchange and confirmation codes for encrypted ESP-NOW peers.
+//!
+//! Every device has an X25519 key pair generated on boot. The public key
+//! is sent in the discovery broadcast. When a device receives the key
+//! of another device, both can derive the same shared secret. From the secret,
+//! we derive the ESP-NOW LMK (local master key) for the pair and a 6-digit
+//! confirmation code. The code is shown on both devices, and the pairing is
+//! confirmed by the user only if the codes match. A man-in-the-middle attacker
+//! would have a different shared secret with each device and so different codes.
+//!
+//! Public keys are known in advance, so an attacker could generate key pairs until
+//! one of them gives the same code with both devices. There are only a million codes,
+//! so it wouldn't take long. To prevent that, the code also depends on a random nonce
+//! from each side, and the nonces are exchanged using commit and reveal, similar to
+//! the Bluetooth numeric comparison:
+//!
+//! 1. Each side sends the commitment: the hash of its public key and its nonce.
+//! 2. When a side receives the commitment of the peer, it reveals its nonce.
+//! 3. When a side receives the nonce of the peer, it checks it against the commitment
+//!    and shows the code.
+//!
+//! The attacker has to commit to its key and nonce before learning the nonce
+//! of the other side, so it has only one guess at the code.
+//!
+//! Pairing messages are broadcast with the address of the recipient inside, because
+//! the recipient might not be added as an ESP-NOW peer yet. Lost messages are sent
+//! again when the next beacon of the peer is received.
+//!
+//! The PMK (primary master key) used by ESP-NOW to encrypt LMKs must be the same
+//! on all devices, so it's a constant. The secrecy comes from LMKs.
+use alloc::vec::Vec;
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// The size of a public key in bytes.
+pub const KEY_SIZE: usize = 32;
+/// The size of a pairing nonce in bytes.
+pub const NONCE_SIZE: usize = 16;
+/// The size of a commitment in bytes.
+pub const COMMIT_SIZE: usize = 32;
+/// Max number of pairings in progress or waiting for confirmation.
+pub const MAX_PENDING: usize = 8;
+/// How long (in milliseconds) a pairing can stay unconfirmed.
+pub const TIMEOUT: u64 = 60_000;
+/// The number of distinct confirmation codes.
+const CODES: u32 = 1_000_000;
+
+/// The first bytes of every pairing message.
+const MAGIC: &[u8; 4] = b"FFZP";
+const KIND_COMMIT: u8 = 1;
+const KIND_REVEAL: u8 = 2;
+
+/// The PMK shared by all devices.
+pub const PMK: [u8; 16] = *b"firefly-zero-pmk";
+
+type Addr = [u8; 6];
+type Nonce = [u8; NONCE_SIZE];
+type Commitment = [u8; COMMIT_SIZE];
+
+/// Keys derived from the key exchange with a peer.
+#[derive(Clone, Copy)]
+pub struct SessionKeys {
+    /// The ESP-NOW LMK for the peer.
+    pub lmk: [u8; 16],
+    /// The 6-digit code to show to the user.
+    pub code: u32,
+}
+
+/// A pairing message sent to a peer.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Message {
+    /// The hash of the sender's public key and nonce.
+    Commit(Commitment),
+    /// The sender's nonce.
+    Reveal(Nonce),
+}
+
+impl Message {
+    /// Serialize the message for the given recipient.
+    #[must_use]
+    pub fn encode(&self, to: Addr) -> Vec<u8> {
+        let (kind, payload): (u8, &[u8]) = match self {
+            Self::Commit(commit) => (KIND_COMMIT, commit),
+            Self::Reveal(nonce) => (KIND_REVEAL, nonce),
+        };
+        let mut raw = Vec::with_capacity(MAGIC.len() + 1 + to.len() + payload.len());
+        raw.extend_from_slice(MAGIC);
+        raw.push(kind);
+        raw.extend_from_slice(&to);
+        raw.extend_from_slice(payload);
+        raw
+    }
+
+    /// Parse the message and its recipient. Returns None if the payload is not a pairing message.
+    #[must_use]
+    pub fn decode(raw: &[u8]) -> Option<(Addr, Self)> {
+        let raw = raw.strip_prefix(MAGIC)?;
+        let (&kind, raw) = raw.split_first()?;
+        let (&to, raw) = raw.split_first_chunk()?;
+        let msg = match kind {
+            KIND_COMMIT => Self::Commit(raw.try_into().ok()?),
+            KIND_REVEAL => Self::Reveal(raw.try_into().ok()?),
+            _ => return None,
+        };
+        Some((to, msg))
+    }
+}
+
+/// The local key pair.
+pub struct Identity {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl Identity {
+    /// Create the key pair from 32 random bytes.
+    #[must_use]
+    pub fn new(seed: [u8; KEY_SIZE]) -> Self {
+        let secret = StaticSecret::from(seed);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    #[must_use]
+    pub fn public(&self) -> [u8; KEY_SIZE] {
+        self.public.to_bytes()
+    }
+
+    /// Derive the session keys for the peer with the given public key and nonces.
+    ///
+    /// Both sides derive the same keys. Returns None if the remote key
+    /// is a low-order point that would produce a predictable secret.
+    #[must_use]
+    pub fn agree(&self, remote: [u8; KEY_SIZE], local: Nonce, peer: Nonce) -> Option<SessionKeys> {
+        let remote = PublicKey::from(remote);
+        let shared = self.secret.diffie_hellman(&remote);
+        if !shared.was_contributory() {
+            return None;
+        }
+        // The derivation must not depend on which side is local, so the keys are sorted.
+        let (a, b) = (self.public.as_bytes(), remote.as_bytes());
+        let (first, second, nonces) = if a < b {
+            (a, b, [local, peer])
+        } else {
+            (b, a, [peer, local])
+        };
+        let mut salt = [0u8; KEY_SIZE * 2];
+        salt[..KEY_SIZE].copy_from_slice(first);
+        salt[KEY_SIZE..].copy_from_slice(second);
+
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes());
+        let mut lmk = [0u8; 16];
+        hkdf.expand(b"firefly lmk", &mut lmk).ok()?;
+        let mut code = [0u8; 4];
+        hkdf.expand_multi_info(&[b"firefly code", &nonces[0], &nonces[1]], &mut code)
+            .ok()?;
+        Some(SessionKeys {
+            lmk,
+            code: u32::from_le_bytes(code) % CODES,
+        })
+    }
+}
+
+/// The commitment to the public key and the nonce.
+#[must_use]
+pub fn commit(key: [u8; KEY_SIZE], nonce: Nonce) -> Commitment {
+    let mut hasher = Sha256::new();
+    hasher.update(b"firefly commit");
+    hasher.update(key);
+    hasher.update(nonce);
+    hasher.finalize().into()
+}
+
+/// A key exchange with a peer waiting for the user to confirm the code.
+#[derive(Clone, Copy)]
+pub struct Pending {
+    pub addr: Addr,
+    pub keys: SessionKeys,
+}
+
+/// A key exchange with a peer in progress.
+struct Session {
+    addr: Addr,
+    /// The public key of the peer from its beacon.
+    remote: [u8; KEY_SIZE],
+    /// The local nonce for this session.
+    nonce: Nonce,
+    /// The commitment received from the peer.
+    peer_commit: Option<Commitment>,
+    /// The derived keys, when the peer has revealed its nonce.
+    keys: Option<SessionKeys>,
+    /// When the session was started.
+    started: u64,
+}
+
+pub struct Pairing {
+    identity: Identity,
+    /// If only paired (encrypted) peers are accepted.
+    pub required: bool,
+    sessions: Vec<Session>,
+}
+
+impl Pairing {
+    #[must_use]
+    pub const fn new(identity: Identity) -> Self {
+        Self {
+            identity,
+            required: false,
+            sessions: Vec::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn public(&self) -> [u8; KEY_SIZE] {
+        self.identity.public()
+    }
+
+    /// Start pairing with the peer that sent its public key in a beacon.
+    ///
+    /// The nonce must be random and is used only if a new session is started.
+    /// If the peer has a different key than before (it might have rebooted and got
+    /// a new key pair), the pairing starts over. Returns the messages to send
+    /// to the peer: the ones it might have missed for the current session.
+    ///
+    /// Sessions not confirmed within [`TIMEOUT`] are dropped. If there are too many
+    /// sessions, the oldest one that has no code yet is dropped, so that peers
+    /// that never finish the exchange can't block pairing with anyone else.
+    pub fn offer(
+        &mut self,
+        addr: Addr,
+        remote: [u8; KEY_SIZE],
+        nonce: Nonce,
+        now: u64,
+    ) -> Vec<Message> {
+        let local = self.identity.public();
+        self.sessions
+            .retain(|s| now.saturating_sub(s.started) <= TIMEOUT);
+        if let Some(session) = self.sessions.iter_mut().find(|s| s.addr == addr) {
+            if session.remote != remote {
+                *session = Session::new(addr, remote, nonce, now);
+            }
+            return session.messages(local);
+        }
+        if self.sessions.len() >= MAX_PENDING {
+            let unconfirmed = self.sessions.iter().enumerate();
+            let unconfirmed = unconfirmed.filter(|(_, s)| s.keys.is_none());
+            let Some((index, _)) = unconfirmed.min_by_key(|(_, s)| s.started) else {
+                return Vec::new();
+            };
+            self.sessions.swap_remove(index);
+        }
+        let session = Session::new(addr, remote, nonce, now);
+        let msgs = session.messages(local);
+        self.sessions.push(session);
+        msgs
+    }
+
+    /// Handle a pairing message from the peer.
+    ///
+    /// The nonce must be random and is used only if the session has to start over.
+    /// Returns the messages to send back to the peer.
+    pub fn receive(&mut self, addr: Addr, msg: Message, nonce: Nonce) -> Vec<Message> {
+        let local = self.identity.public();
+        // The key of the peer is learned from its beacon, so the session must exist.
+        let Some(index) = self.sessions.iter().position(|s| s.addr == addr) else {
+            return Vec::new();
+        };
+        let session = &mut self.sessions[index];
+        match msg {
+            Message::Commit(peer_commit) => {
+                if session.peer_commit.is_some_and(|c| c != peer_commit) {
+                    // The peer has started over. Our nonce might be already revealed,
+                    // so it must not be reused with the new commitment. The start time
+                    // is kept, so that starting over doesn't keep the session alive.
+                    *session = Session::new(addr, session.remote, nonce, session.started);
+                }
+                session.peer_commit = Some(peer_commit);
+                session.messages(local)
+            }
+            Message::Reveal(peer_nonce) => {
+                let Some(peer_commit) = session.peer_commit else {
+                    // We haven't revealed our nonce yet, so the peer shouldn't have either.
+                    return Vec::new();
+                };
+                if session.keys.is_some() {
+                    return Vec::new();
+                }
+                if commit(session.remote, peer_nonce) != peer_commit {
+                    self.sessions.swap_remove(index);
+                    return Vec::new();
+                }
+                match self
+                    .identity
+                    .agree(session.remote, session.nonce, peer_nonce)
+                {
+                    Some(keys) => session.keys = Some(keys),
+                    None => _ = self.sessions.swap_remove(index),
+                }
+                Vec::new()
+            }
+        }
+    }
+
+    /// Pairings waiting for confirmation.
+    #[must_use]
+    pub fn pending(&self) -> Vec<Pending> {
+        let sessions = self.sessions.iter();
+        let keys = sessions.filter_map(|s| {
+            Some(Pending {
+                addr: s.addr,
+                keys: s.keys?,
+            })
+        });
+        keys.collect()
+    }
+
+    /// Confirm the pairing and get the LMK for the peer.
+    ///
+    /// Returns None if there is no pairing with the peer or its code isn't known yet.
+    pub fn confirm(&mut self, addr: Addr) -> Option<[u8; 16]> {
+        let index = self.sessions.iter().position(|s| s.addr == addr)?;
+        let keys = self.sessions[index].keys?;
+        self.sessions.swap_remove(index);
+        Some(keys.lmk)
+    }
+
+    /// Cancel the pairing. Returns false if there is no pairing in progress with the peer.
+    pub fn reject(&mut self, addr: Addr) -> bool {
+        let len = self.sessions.len();
+        self.sessions.retain(|s| s.addr != addr);
+        self.sessions.len() != len
+    }
+
+    /// Cancel all pairings in progress.
+    pub fn clear(&mut self) {
+        self.sessions.clear();
+    }
+}
+
+impl Session {
+    const fn new(addr: Addr, remote: [u8; KEY_SIZE], nonce: Nonce, started: u64) -> Self {
+        Self {
+            addr,
+            remote,
+            nonce,
+            peer_commit: None,
+            keys: None,
+            started,
+        }
+    }
+
+    /// The messages the peer needs from us at this point of the exchange.
+    ///
+    /// The nonce is revealed only after the commitment of the peer is received.
+    fn messages(&self, local: [u8; KEY_SIZE]) -> Vec<Message> {
+        let mut msgs = Vec::with_capacity(2);
+        msgs.push(Message::Commit(commit(local, self.nonce)));
+        if self.peer_commit.is_some() {
+            msgs.push(Message::Reveal(self.nonce));
+        }
+        msgs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: Addr = [1; 6];
+    const B: Addr = [2; 6];
+
+    fn identity(seed: u8) -> Identity {
+        Identity::new([seed; KEY_SIZE])
+    }
+
+    /// Deliver messages back and forth until both sides have nothing to say.
+    fn exchange(a: &mut Pairing, b: &mut Pairing) {
+        let mut to_b = a.offer(B, b.public(), [0xA1; NONCE_SIZE], 0);
+        let mut to_a = b.offer(A, a.public(), [0xB1; NONCE_SIZE], 0);
+        for _ in 0..4 {
+            let next_b: Vec<_> = to_a
+                .iter()
+                .flat_map(|&msg| a.receive(B, msg, [0xA2; NONCE_SIZE]))
+                .collect();
+            let next_a: Vec<_> = to_b
+                .iter()
+                .flat_map(|&msg| b.receive(A, msg, [0xB2; NONCE_SIZE]))
+                .collect();
+            to_a = next_a;
+            to_b = next_b;
+        }
+    }
+
+    #[test]
+    fn message_roundtrip() {
+        for msg in [
+            Message::Commit([7; COMMIT_SIZE]),
+            Message::Reveal([9; NONCE_SIZE]),
+        ] {
+            let raw = msg.encode(B);
+            assert_eq!(Message::decode(&raw), Some((B, msg)));
+            assert_eq!(Message::decode(&raw[..raw.len() - 1]), None);
+        }
+        assert_eq!(Message::decode(b"FFZB\x01"), None);
+    }
+
+    #[test]
+    fn agree_is_symmetric() {
+        let (a, b) = (identity(1), identity(2));
+        let (na, nb) = ([3; NONCE_SIZE], [4; NONCE_SIZE]);
+        let ka = a.agree(b.public(), na, nb).unwrap();
+        let kb = b.agree(a.public(), nb, na).unwrap();
+        assert_eq!(ka.lmk, kb.lmk);
+        assert_eq!(ka.code, kb.code);
+        assert!(ka.code < CODES);
+
+        // The code depends on the nonces, the LMK doesn't.
+        let other = a.agree(b.public(), [5; NONCE_SIZE], nb).unwrap();
+        assert_eq!(other.lmk, ka.lmk);
+        assert_ne!(other.code, ka.code);
+        // Swapping the nonces between the sides changes the code.
+        let swapped = a.agree(b.public(), nb, na).unwrap();
+        assert_ne!(swapped.code, ka.code);
+    }
+
+    #[test]
+    fn agree_matches_hkdf() {
+        let (a, b) = (identity(1), identity(2));
+        let (na, nb) = ([3; NONCE_SIZE], [4; NONCE_SIZE]);
+        let keys = a.agree(b.public(), na, nb).unwrap();
+
+        let shared = StaticSecret::from([1; KEY_SIZE]).diffie_hellman(&PublicKey::from(b.public()));
+        let (pa, pb) = (a.public(), b.public());
+        let (salt, info): ([&[u8]; 2], [&[u8]; 3]) = if pa < pb {
+            ([&pa, &pb], [b"firefly code", &na, &nb])
+        } else {
+            ([&pb, &pa], [b"firefly code", &nb, &na])
+        };
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt.concat()), shared.as_bytes());
+        let mut lmk = [0u8; 16];
+        hkdf.expand(b"firefly lmk", &mut lmk).unwrap();
+        let mut code = [0u8; 4];
+        hkdf.expand(&info.concat(), &mut code).unwrap();
+        assert_eq!(keys.lmk, lmk);
+        assert_eq!(keys.code, u32::from_le_bytes(code) % CODES);
+    }
+
+    #[test]
+    fn agree_rejects_low_order_key() {
+        let a = identity(1);
+        assert!(a
+            .agree([0; KEY_SIZE], [0; NONCE_SIZE], [0; NONCE_SIZE])
+            .is_none());
+    }
+
+    #[test]
+    fn commit_binds_key_and_nonce() {
+        let key = identity(1).public();
+        let c = commit(key, [1; NONCE_SIZE]);
+        assert_eq!(c, commit(key, [1; NONCE_SIZE]));
+        assert_ne!(c, commit(key, [2; NONCE_SIZE]));
+        assert_ne!(c, commit(identity(2).public(), [1; NONCE_SIZE]));
+    }
+
+    #[test]
+    fn pair() {
+        let mut a = Pairing::new(identity(1));
+        let mut b = Pairing::new(identity(2));
+        exchange(&mut a, &mut b);
+        let (pa, pb) = (a.pending(), b.pending());
+        assert_eq!(pa.len(), 1);
+        assert_eq!(pb.len(), 1);
+        assert_eq!(pa[0].addr, B);
+        assert_eq!(pb[0].addr, A);
+        assert_eq!(pa[0].keys.code, pb[0].keys.code);
+        assert_eq!(a.confirm(B), b.confirm(A));
+        assert!(a.pending().is_empty());
+        assert!(a.confirm(B).is_none());
+    }
+
+    #[test]
+    fn reveal_only_after_commit() {
+        let mut a = Pairing::new(identity(1));
+        let b = identity(2);
+        let msgs = a.offer(B, b.public(), [1; NONCE_SIZE], 0);
+        assert_eq!(msgs, [Message::Commit(commit(a.public(), [1; NONCE_SIZE]))]);
+        // A reveal before the commitment is ignored and the code isn't shown.
+        let msgs = a.receive(B, Message::Reveal([2; NONCE_SIZE]), [0; NONCE_SIZE]);
+        assert!(msgs.is_empty());
+        assert!(a.pending().is_empty());
+
+        let peer_commit = Message::Commit(commit(b.public(), [2; NONCE_SIZE]));
+        let msgs = a.receive(B, peer_commit, [0; NONCE_SIZE]);
+        assert_eq!(msgs[1], Message::Reveal([1; NONCE_SIZE]));
+        a.receive(B, Message::Reveal([2; NONCE_SIZE]), [0; NONCE_SIZE]);
+        assert_eq!(a.pending().len(), 1);
+    }
+
+    #[test]
+    fn wrong_reveal_cancels() {
+        let mut a = Pairing::new(identity(1));
+        let b = identity(2);
+        a.offer(B, b.public(), [1; NONCE_SIZE], 0);
+        let peer_commit = Message::Commit(commit(b.public(), [2; NONCE_SIZE]));
+        a.receive(B, peer_commit, [0; NONCE_SIZE]);
+        a.receive(B, Message::Reveal([3; NONCE_SIZE]), [0; NONCE_SIZE]);
+        assert!(a.pending().is_empty());
+        assert!(!a.reject(B));
+    }
+
+    #[test]
+    fn new_commit_uses_new_nonce() {
+        let mut a = Pairing::new(identity(1));
+        let b = identity(2);
+        a.offer(B, b.public(), [1; NONCE_SIZE], 0);
+        let first = Message::Commit(commit(b.public(), [2; NONCE_SIZE]));
+        let msgs = a.receive(B, first, [5; NONCE_SIZE]);
+        assert_eq!(msgs[1], Message::Reveal([1; NONCE_SIZE]));
+        // The attacker learned the nonce and tries to commit again.
+        let second = Message::Commit(commit(b.public(), [3; NONCE_SIZE]));
+        let msgs = a.receive(B, second, [5; NONCE_SIZE]);
+        assert_eq!(
+            msgs,
+            [
+                Message::Commit(commit(a.public(), [5; NONCE_SIZE])),
+                Message::Reveal([5; NONCE_SIZE]),
+            ]
+        );
+    }
+
+    #[test]
+    fn new_key_starts_over() {
+        let mut a = Pairing::new(identity(1));
+        let mut b = Pairing::new(identity(2));
+        exchange(&mut a, &mut b);
+        let msgs = a.offer(B, identity(3).public(), [7; NONCE_SIZE], 0);
+        assert_eq!(msgs, [Message::Commit(commit(a.public(), [7; NONCE_SIZE]))]);
+        assert!(a.pending().is_empty());
+        // The same key resends the messages of the current session.
+        let msgs = a.offer(B, identity(3).public(), [8; NONCE_SIZE], 0);
+        assert_eq!(msgs, [Message::Commit(commit(a.public(), [7; NONCE_SIZE]))]);
+    }
+
+    #[test]
+    fn limit_sessions() {
+        let mut a = Pairing::new(identity(1));
+        let mut b = Pairing::new(identity(2));
+        exchange(&mut a, &mut b);
+        let key = identity(3).public();
+        for i in 1..MAX_PENDING {
+            let addr = [u8::try_from(i).unwrap() + 10; 6];
+            let now = u64::try_from(i).unwrap();
+            assert!(!a.offer(addr, key, [0; NONCE_SIZE], now).is_empty());
+        }
+        // The oldest session without a code makes room for the new one.
+        assert!(!a.offer([0xFF; 6], key, [0; NONCE_SIZE], 100).is_empty());
+        assert!(!a.reject([11; 6]));
+        assert!(a.reject([12; 6]));
+        // The session waiting for confirmation is kept.
+        assert_eq!(a.pending().len(), 1);
+        a.clear();
+        assert!(!a.offer([0xFF; 6], key, [0; NONCE_SIZE], 100).is_empty());
+    }
+
+    #[test]
+    fn sessions_expire() {
+        let mut a = Pairing::new(identity(1));
+        let mut b = Pairing::new(identity(2));
+        exchange(&mut a, &mut b);
+        let key = identity(3).public();
+        a.offer([9; 6], key, [1; NONCE_SIZE], TIMEOUT);
+        a.offer([9; 6], key, [2; NONCE_SIZE], TIMEOUT);
+        assert_eq!(a.pending().len(), 1);
+        a.offer([9; 6], key, [3; NONCE_SIZE], TIMEOUT + 1);
+        assert!(a.pending().is_empty());
+        assert!(a.confirm(B).is_none());
+        // The expired session starts over with a new nonce.
+        let msgs = a.offer([9; 6], key, [4; NONCE_SIZE], TIMEOUT * 2 + 1);
+        assert_eq!(msgs, [Message::Commit(commit(a.public(), [4; NONCE_SIZE]))]);
+    }
+}
@@ -0,0 +1,185 @@
+//! The table of ESP-NOW peers with metadata and the access list.
+//!
+//! ESP-NOW itself keeps only what's needed to send frames. Here we track
+//! when each peer was seen and how strong its signal is, so that the main
+//! chip can show it to the user and decide whom to keep connected.
+use alloc::vec::Vec;
+
+/// Max number of peers. It's the limit of ESP-NOW.
+pub const MAX_PEERS: usize = 20;
+/// Max number of devices in each of the allow and block lists.
+pub const MAX_ACCESS: usize = 16;
+
+type Addr = [u8; 6];
+
+#[derive(Clone, Copy)]
+pub struct PeerMeta {
+    pub addr: Addr,
+    /// When the first packet from the peer was received (ms since boot).
+    pub first_seen: u64,
+    /// When the latest packet from the peer was received (ms since boot).
+    pub last_seen: u64,
+    /// The signal strength of the latest packet in dBm.
+    pub rssi: i8,
+    /// If the traffic with the peer is encrypted.
+    pub encrypted: bool,
+}
+
+/// How a device is treated when it tries to connect.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Use the default policy.
+    Default,
+    /// Accept the device. If there is at least one allowed device,
+    /// all other devices are rejected.
+    Allow,
+    /// Never accept the device.
+    Block,
+}
+
+impl TryFrom<u8> for Access {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        let access = match value {
+            0 => Self::Default,
+            1 => Self::Allow,
+            2 => Self::Block,
+            _ => return Err(()),
+        };
+        Ok(access)
+    }
+}
+
+#[derive(Default)]
+pub struct Peers {
+    known: Vec<PeerMeta>,
+    allowed: Vec<Addr>,
+    blocked: Vec<Addr>,
+}
+
+impl Peers {
+    /// Check if the device may become a peer.
+    #[must_use]
+    pub fn is_allowed(&self, addr: &Addr) -> bool {
+        if self.blocked.contains(addr) {
+            return false;
+        }
+        self.allowed.is_empty() || self.allowed.contains(addr)
+    }
+
+    /// Set how the device is treated. Returns false if the list is full.
+    pub fn set_access(&mut self, addr: Addr, access: Access) -> bool {
+        self.allowed.retain(|a| *a != addr);
+        self.blocked.retain(|a| *a != addr);
+        let list = match access {
+            Access::Default => return true,
+            Access::Allow => &mut self.allowed,
+            Access::Block => &mut self.blocked,
+        };
+        if list.len() >= MAX_ACCESS {
+            return false;
+        }
+        list.push(addr);
+        true
+    }
+
+    /// Record that a packet from the peer has been received.
+    ///
+    /// The encryption flag comes from the ESP-NOW peer info, so that it stays correct
+    /// even if the peer was added only when the pairing was confirmed.
+    pub fn seen(&mut self, addr: Addr, now: u64, rssi: i8, encrypted: bool) {
+        if let Some(peer) = self.known.iter_mut().find(|p| p.addr == addr) {
+            peer.last_seen = now;
+            peer.rssi = rssi;
+            peer.encrypted = encrypted;
+            return;
+        }
+        if self.known.len() >= MAX_PEERS {
+            return;
+        }
+        self.known.push(PeerMeta {
+            addr,
+            first_seen: now,
+            last_seen: now,
+            rssi,
+            encrypted,
+        });
+    }
+
+    /// Mark the known peer as encrypted right after its pairing is confirmed.
+    pub fn set_encrypted(&mut self, addr: Addr) {
+        if let Some(peer) = self.known.iter_mut().find(|p| p.addr == addr) {
+            peer.encrypted = true;
+        }
+    }
+
+    #[must_use]
+    pub fn get(&self, index: usize) -> Option<PeerMeta> {
+        self.known.get(index).copied()
+    }
+
+    #[must_use]
+    pub const fn len(&self) -> usize {
+        self.known.len()
+    }
+
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.known.is_empty()
+    }
+
+    pub fn remove(&mut self, addr: Addr) {
+        self.known.retain(|p| p.addr != addr);
+    }
+
+    /// Forget all peers. The access list is kept.
+    pub fn clear(&mut self) {
+        self.known.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: Addr = [1; 6];
+
+    #[test]
+    fn seen_updates_peer() {
+        let mut peers = Peers::default();
+        peers.seen(A, 10, -40, false);
+        peers.seen(A, 20, -50, false);
+        assert_eq!(peers.len(), 1);
+        let peer = peers.get(0).unwrap();
+        assert_eq!(peer.first_seen, 10);
+        assert_eq!(peer.last_seen, 20);
+        assert_eq!(peer.rssi, -50);
+        assert!(!peer.encrypted);
+
+        peers.set_encrypted(A);
+        assert!(peers.get(0).unwrap().encrypted);
+    }
+
+    #[test]
+    fn encrypted_on_first_packet() {
+        // With pairing required, the peer is added only when the pairing is confirmed,
+        // so the first packet from it is already encrypted.
+        let mut peers = Peers::default();
+        peers.set_encrypted(A);
+        assert!(peers.is_empty());
+        peers.seen(A, 10, -40, true);
+        assert!(peers.get(0).unwrap().encrypted);
+    }
+
+    #[test]
+    fn max_peers() {
+        let mut peers = Peers::default();
+        for i in 0..=MAX_PEERS {
+            peers.seen([u8::try_from(i).unwrap(); 6], 0, 0, false);
+        }
+        assert_eq!(peers.len(), MAX_PEERS);
+        peers.remove([0; 6]);
+        assert_eq!(peers.len(), MAX_PEERS - 1);
+    }
+}
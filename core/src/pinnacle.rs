@@ -0,0 +1,338 @@
+//! A minimal driver for Cirque Pinnacle touchpad in absolute mode.
+//!
+//! Based on the Cirque application notes GT-AN-090620 and GT-AN-090625.
+use crate::touch::AdcGain;
+use embedded_hal::spi::{Operation, SpiDevice};
+
+const READ_MASK: u8 = 0xA0;
+const WRITE_MASK: u8 = 0x80;
+const FILLER: u8 = 0xFC;
+/// The expected value of [`REG_FIRMWARE_ID`].
+const FIRMWARE_ID: u8 = 0x07;
+
+const REG_FIRMWARE_ID: u8 = 0x00;
+const REG_STATUS1: u8 = 0x02;
+const REG_SYS_CONFIG1: u8 = 0x03;
+const REG_FEED_CONFIG1: u8 = 0x04;
+const REG_FEED_CONFIG2: u8 = 0x05;
+const REG_CAL_CONFIG1: u8 = 0x07;
+const REG_Z_IDLE: u8 = 0x0A;
+const REG_ERA_VALUE: u8 = 0x1B;
+const REG_ERA_ADDR_HIGH: u8 = 0x1C;
+const REG_ERA_ADDR_LOW: u8 = 0x1D;
+const REG_ERA_CONTROL: u8 = 0x1E;
+const REG_PACKET: u8 = 0x12;
+
+/// Feed enabled, absolute mode.
+const FEED_CONFIG1: u8 = 0b0000_0011;
+/// Set in [`REG_FEED_CONFIG1`] to disable the built-in noise filter.
+const FEED_FILTER_DISABLE: u8 = 0b0000_0100;
+/// Disable taps, secondary taps, scroll, and other extensions.
+const FEED_CONFIG2: u8 = 0x1F;
+
+const ERA_ADC_ATTENUATION: u16 = 0x0187;
+const ERA_READ: u8 = 0x01;
+const ERA_WRITE: u8 = 0x02;
+
+/// How many times to poll the chip for a long operation (ERA access, calibration)
+/// before giving up.
+const MAX_POLLS: u16 = 1000;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Spi(E),
+    /// The touchpad hasn't finished the operation in time.
+    Timeout,
+    /// The touchpad doesn't respond with the expected firmware ID.
+    ///
+    /// Most likely, it's not connected.
+    NotFound,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(value: E) -> Self {
+        Self::Spi(value)
+    }
+}
+
+/// Raw data packet in absolute mode.
+pub struct AbsoluteData {
+    pub x: u16,
+    pub y: u16,
+    pub z: u8,
+}
+
+pub struct Pinnacle<S> {
+    spi: S,
+}
+
+impl<S: SpiDevice> Pinnacle<S> {
+    pub const fn new(spi: S) -> Self {
+        Self { spi }
+    }
+
+    /// Configure the touchpad for absolute mode with the given ADC gain and filtering.
+    ///
+    /// Can be called again at any time to change the settings.
+    pub fn init(&mut self, gain: AdcGain, filter: bool) -> Result<(), Error<S::Error>> {
+        if self.read_byte(REG_FIRMWARE_ID)? != FIRMWARE_ID {
+            return Err(Error::NotFound);
+        }
+        self.clear_flags()?;
+        self.write(REG_SYS_CONFIG1, 0x00)?;
+        self.write(REG_FEED_CONFIG1, 0x00)?;
+        self.write(REG_FEED_CONFIG2, FEED_CONFIG2)?;
+        self.write(REG_Z_IDLE, 0x05)?;
+        self.set_adc_gain(gain)?;
+        self.calibrate()?;
+        let mut feed = FEED_CONFIG1;
+        if !filter {
+            feed |= FEED_FILTER_DISABLE;
+        }
+        self.write(REG_FEED_CONFIG1, feed)?;
+        Ok(())
+    }
+
+    /// Read the latest absolute data packet.
+    pub fn read_absolute(&mut self) -> Result<AbsoluteData, Error<S::Error>> {
+        let mut buf = [0u8; 6];
+        self.read(REG_PACKET, &mut buf)?;
+        self.clear_flags()?;
+        Ok(AbsoluteData {
+            x: u16::from(buf[2]) | (u16::from(buf[4] & 0x0F) << 8),
+            y: u16::from(buf[3]) | (u16::from(buf[4] & 0xF0) << 4),
+            z: buf[5] & 0x3F,
+        })
+    }
+
+    /// Set sensitivity of the touchpad.
+    ///
+    /// The feed must be disabled.
+    fn set_adc_gain(&mut self, gain: AdcGain) -> Result<(), Error<S::Error>> {
+        let val = self.era_read(ERA_ADC_ATTENUATION)?;
+        let val = (val & 0x3F) | (u8::from(gain) << 6);
+        self.era_write(ERA_ADC_ATTENUATION, val)
+    }
+
+    /// Force the touchpad to recalibrate and wait for it to finish.
+    ///
+    /// Must be done after changing the ADC gain. Don't touch the pad while it's happening.
+    fn calibrate(&mut self) -> Result<(), Error<S::Error>> {
+        let val = self.read_byte(REG_CAL_CONFIG1)?;
+        self.write(REG_CAL_CONFIG1, val | 0x01)?;
+        self.wait_for(REG_CAL_CONFIG1, 0x01)?;
+        self.clear_flags()
+    }
+
+    fn era_read(&mut self, addr: u16) -> Result<u8, Error<S::Error>> {
+        let [high, low] = addr.to_be_bytes();
+        self.write(REG_ERA_ADDR_HIGH, high)?;
+        self.write(REG_ERA_ADDR_LOW, low)?;
+        self.write(REG_ERA_CONTROL, ERA_READ)?;
+        self.wait_for(REG_ERA_CONTROL, 0xFF)?;
+        let val = self.read_byte(REG_ERA_VALUE)?;
+        self.clear_flags()?;
+        Ok(val)
+    }
+
+    fn era_write(&mut self, addr: u16, val: u8) -> Result<(), Error<S::Error>> {
+        let [high, low] = addr.to_be_bytes();
+        self.write(REG_ERA_VALUE, val)?;
+        self.write(REG_ERA_ADDR_HIGH, high)?;
+        self.write(REG_ERA_ADDR_LOW, low)?;
+        self.write(REG_ERA_CONTROL, ERA_WRITE)?;
+        self.wait_for(REG_ERA_CONTROL, 0xFF)?;
+        self.clear_flags()
+    }
+
+    /// Wait until all the bits from the mask are cleared in the register.
+    fn wait_for(&mut self, reg: u8, mask: u8) -> Result<(), Error<S::Error>> {
+        for _ in 0..MAX_POLLS {
+            if self.read_byte(reg)? & mask == 0 {
+                return Ok(());
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    fn clear_flags(&mut self) -> Result<(), Error<S::Error>> {
+        self.write(REG_STATUS1, 0x00)
+    }
+
+    fn read_byte(&mut self, reg: u8) -> Result<u8, Error<S::Error>> {
+        let mut buf = [0u8];
+        self.read(reg, &mut buf)?;
+        Ok(buf[0])
+    }
+
+    /// Read consecutive registers using Register Access Protocol (RAP).
+    fn read(&mut self, reg: u8, buf: &mut [u8]) -> Result<(), Error<S::Error>> {
+        buf.fill(FILLER);
+        self.spi.transaction(&mut [
+            Operation::Write(&[READ_MASK | reg, FILLER, FILLER]),
+            Operation::TransferInPlace(buf),
+        ])?;
+        Ok(())
+    }
+
+    fn write(&mut self, reg: u8, val: u8) -> Result<(), Error<S::Error>> {
+        self.spi.write(&[WRITE_MASK | reg, val])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::Infallible;
+    use embedded_hal::spi::ErrorType;
+    use std::vec::Vec;
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Op {
+        Read(u8),
+        Write(u8, u8),
+    }
+
+    /// A fake touchpad that finishes every long operation instantly.
+    struct Mock {
+        regs: [u8; 0x20],
+        era: u8,
+        log: Vec<Op>,
+    }
+
+    impl Mock {
+        fn new() -> Self {
+            let mut regs = [0; 0x20];
+            regs[usize::from(REG_FIRMWARE_ID)] = FIRMWARE_ID;
+            Self {
+                regs,
+                era: 0b0010_1010,
+                log: Vec::new(),
+            }
+        }
+    }
+
+    impl ErrorType for Mock {
+        type Error = Infallible;
+    }
+
+    impl SpiDevice for Mock {
+        fn transaction(&mut self, ops: &mut [Operation<'_, u8>]) -> Result<(), Infallible> {
+            match ops {
+                [Operation::Write(&[cmd, val])] => {
+                    assert_eq!(cmd & 0xE0, WRITE_MASK);
+                    let reg = cmd & 0x1F;
+                    self.log.push(Op::Write(reg, val));
+                    match reg {
+                        REG_ERA_CONTROL if val == ERA_READ => {
+                            self.regs[usize::from(REG_ERA_VALUE)] = self.era;
+                        }
+                        REG_ERA_CONTROL if val == ERA_WRITE => {
+                            self.era = self.regs[usize::from(REG_ERA_VALUE)];
+                        }
+                        _ => self.regs[usize::from(reg)] = val,
+                    }
+                    // ERA access and calibration complete immediately.
+                    self.regs[usize::from(REG_ERA_CONTROL)] = 0;
+                    self.regs[usize::from(REG_CAL_CONFIG1)] &= !0x01;
+                }
+                [Operation::Write(&[cmd, FILLER, FILLER]), Operation::TransferInPlace(buf)] => {
+                    assert_eq!(cmd & 0xE0, READ_MASK);
+                    let reg = cmd & 0x1F;
+                    self.log.push(Op::Read(reg));
+                    for (i, b) in buf.iter_mut().enumerate() {
+                        *b = self.regs[usize::from(reg) + i];
+                    }
+                }
+                _ => panic!("unexpected transaction"),
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn init_sequence() {
+        let mut pad = Pinnacle::new(Mock::new());
+        pad.init(AdcGain::X3, false).unwrap();
+        let era_high = 0x01;
+        let era_low = 0x87;
+        let expected = [
+            Op::Read(REG_FIRMWARE_ID),
+            Op::Write(REG_STATUS1, 0),
+            Op::Write(REG_SYS_CONFIG1, 0),
+            Op::Write(REG_FEED_CONFIG1, 0),
+            Op::Write(REG_FEED_CONFIG2, 0x1F),
+            Op::Write(REG_Z_IDLE, 5),
+            // set_adc_gain: ERA read
+            Op::Write(REG_ERA_ADDR_HIGH, era_high),
+            Op::Write(REG_ERA_ADDR_LOW, era_low),
+            Op::Write(REG_ERA_CONTROL, ERA_READ),
+            Op::Read(REG_ERA_CONTROL),
+            Op::Read(REG_ERA_VALUE),
+            Op::Write(REG_STATUS1, 0),
+            // set_adc_gain: ERA write
+            Op::Write(REG_ERA_VALUE, 0b1010_1010),
+            Op::Write(REG_ERA_ADDR_HIGH, era_high),
+            Op::Write(REG_ERA_ADDR_LOW, era_low),
+            Op::Write(REG_ERA_CONTROL, ERA_WRITE),
+            Op::Read(REG_ERA_CONTROL),
+            Op::Write(REG_STATUS1, 0),
+            // calibrate
+            Op::Read(REG_CAL_CONFIG1),
+            Op::Write(REG_CAL_CONFIG1, 0x01),
+            Op::Read(REG_CAL_CONFIG1),
+            Op::Write(REG_STATUS1, 0),
+            Op::Write(REG_FEED_CONFIG1, 0b111),
+        ];
+        assert_eq!(pad.spi.log, expected);
+        assert_eq!(pad.spi.era, 0b1010_1010);
+    }
+
+    #[test]
+    fn init_with_filter() {
+        let mut pad = Pinnacle::new(Mock::new());
+        pad.init(AdcGain::X1, true).unwrap();
+        assert_eq!(pad.spi.log.last(), Some(&Op::Write(REG_FEED_CONFIG1, 0b11)));
+        assert_eq!(pad.spi.era, 0b0010_1010);
+    }
+
+    #[test]
+    fn init_not_found() {
+        let mut mock = Mock::new();
+        mock.regs[usize::from(REG_FIRMWARE_ID)] = 0;
+        let mut pad = Pinnacle::new(mock);
+        assert!(matches!(pad.init(AdcGain::X1, true), Err(Error::NotFound)));
+        assert_eq!(pad.spi.log, [Op::Read(REG_FIRMWARE_ID)]);
+    }
+
+    #[test]
+    fn init_timeout() {
+        struct Stuck(Mock);
+        impl ErrorType for Stuck {
+            type Error = Infallible;
+        }
+        impl SpiDevice for Stuck {
+            fn transaction(&mut self, ops: &mut [Operation<'_, u8>]) -> Result<(), Infallible> {
+                self.0.transaction(ops)?;
+                self.0.regs[usize::from(REG_ERA_CONTROL)] = ERA_READ;
+                Ok(())
+            }
+        }
+        let mut pad = Pinnacle::new(Stuck(Mock::new()));
+        assert!(matches!(pad.init(AdcGain::X1, true), Err(Error::Timeout)));
+    }
+
+    #[test]
+    fn read_absolute() {
+        let mut mock = Mock::new();
+        let packet = &mut mock.regs[usize::from(REG_PACKET)..][..6];
+        packet.copy_from_slice(&[0, 0, 0x34, 0x78, 0x56, 0x4A]);
+        let mut pad = Pinnacle::new(mock);
+        let data = pad.read_absolute().unwrap();
+        assert_eq!(data.x, 0x634);
+        assert_eq!(data.y, 0x578);
+        assert_eq!(data.z, 0x0A);
+        assert_eq!(pad.spi.log.last(), Some(&Op::Write(REG_STATUS1, 0)));
+    }
+}
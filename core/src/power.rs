@@ -0,0 +1,37 @@
+//! Power settings and wakeup reasons shared with the main chip.
+//!
+//! The sleep itself is managed by the firmware.
+
+#[derive(Clone, Copy, Default)]
+pub struct PowerConfig {
+    /// If the chip may enter light sleep when idle.
+    pub light_sleep: bool,
+    /// How long (in milliseconds) without requests before entering light sleep.
+    pub idle: u16,
+}
+
+#[derive(Clone, Copy, Default)]
+pub enum WakeReason {
+    /// Power-on or reset, not a wakeup from sleep.
+    #[default]
+    Reset,
+    /// Woken up from deep sleep by the menu button.
+    Button,
+    /// The periodic wakeup to run the background work.
+    Timer,
+    /// A request from the main chip.
+    Uart,
+    Other,
+}
+
+impl From<WakeReason> for u8 {
+    fn from(value: WakeReason) -> Self {
+        match value {
+            WakeReason::Reset => 0,
+            WakeReason::Button => 1,
+            WakeReason::Timer => 2,
+            WakeReason::Uart => 3,
+            WakeReason::Other => 4,
+        }
+    }
+}
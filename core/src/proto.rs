@@ -0,0 +1,813 @@
+//! Requests and responses specific to the IO chip.
+//!
+//! These are not part of firefly-types (yet). On the wire, they have the same
+//! framing as regular requests but the payload starts with [`EXT_TAG`].
+//! firefly-types encodes the enum discriminant as a varint and it has
+//! far fewer than 128 variants, so the first byte of a regular request
+//! is always below `0x80` and the two can't be confused.
+//!
+//! The payload of an extension message is the tag, the message kind,
+//! and then all fields in order. Integers are little-endian.
+use crate::{
+    backoff::RetryPolicy,
+    beacon::{Beacon, MAX_APP, MAX_NAME},
+    combo::Combo,
+    filter::FilterConfig,
+    health::PadHealth,
+    input::{AxisCalibration, Capabilities, InputState, INPUT_FORMAT},
+    latency::LatencyStats,
+    layout::ButtonMap,
+    pairing::Pending,
+    peers::{Access, PeerMeta},
+    power::{PowerConfig, WakeReason},
+    replay::Event,
+    sampler::FrameSummary,
+    seq::LinkStats,
+    stick::{StickConfig, StickState},
+    stream::Status as StreamStatus,
+    touch::{PadConfig, Threshold, Touch},
+};
+use alloc::string::String;
+use alloc::vec::Vec;
+use anyhow::{bail, Result};
+
+/// The first byte of every extension request and response.
+pub const EXT_TAG: u8 = 0xFF;
+
+/// The user held the recovery combo.
+pub const FLAG_RECOVERY: u8 = 1 << 0;
+/// There was no button or touch activity for longer than the idle timeout.
+///
+/// Unlike other flags, it's cleared automatically on the next activity.
+pub const FLAG_IDLE: u8 = 1 << 1;
+/// A message too big for `NetRecv` is received and can be read with `RecvLarge`.
+pub const FLAG_LARGE_MESSAGE: u8 = 1 << 2;
+
+/// Max number of input events in a single request or response.
+pub const MAX_EVENTS: usize = 25;
+
+/// Check if the raw request payload is an extension request.
+#[must_use]
+pub fn is_ext(payload: &[u8]) -> bool {
+    payload.first() == Some(&EXT_TAG)
+}
+
+/// What the local device tells about itself in discovery beacons.
+pub struct BeaconInfo {
+    pub name: String,
+    pub app: String,
+    pub lobby: u32,
+    /// Ignore devices running other apps.
+    pub same_app: bool,
+}
+
+pub enum Request {
+    /// Read the touchpad state, including Z level and the virtual stick.
+    ReadTouch,
+    /// Set Z levels at which the pad is considered touched.
+    SetTouchThreshold(Threshold),
+    /// Get the current touchpad configuration.
+    GetPadConfig,
+    /// Apply the new touchpad configuration and persist it.
+    SetPadConfig(PadConfig),
+    /// Check if the touchpad is working.
+    GetPadHealth,
+    /// Configure the virtual D-pad and stick.
+    SetStickConfig(StickConfig),
+    /// Configure smoothing of touchpad coordinates.
+    SetFilter(FilterConfig),
+    /// Set or clear (if None) the button combo in the given slot.
+    SetCombo(u8, Option<Combo>),
+    /// Get the bitmask of `FLAG_*` system flags.
+    GetFlags,
+    /// Clear the given system flags.
+    ClearFlags(u8),
+    /// Set the background input sampling rate in Hz. Zero disables sampling.
+    SetSampleRate(u16),
+    /// Get the summary of input since the last read.
+    ReadFrame,
+    /// Start recording input.
+    StartRecording,
+    /// Start replaying the uploaded input script.
+    StartPlayback,
+    /// Stop recording or playback.
+    StopReplay,
+    /// Read recorded input events starting from the given index.
+    ReadRecording(u16),
+    /// Upload input events for playback at the given index.
+    UploadScript(u16, Vec<Event>),
+    /// Get the mapping of physical button pins to logical buttons.
+    GetButtonMap,
+    /// Set the mapping of physical button pins to logical buttons and persist it.
+    SetButtonMap(ButtonMap),
+    /// Start (and reset) or stop collecting the input latency histogram.
+    SetLatencyMode(bool),
+    /// Get the collected input latency stats.
+    GetLatency,
+    /// Configure light sleep when idle.
+    SetPowerConfig(PowerConfig),
+    /// Get the power config, sleep stats, and the last wake reason.
+    GetPowerStatus,
+    /// Enter deep sleep until the menu button is pressed.
+    DeepSleep,
+    /// Set after how many seconds without input `FLAG_IDLE` is raised. Zero disables it.
+    SetIdleTimeout(u16),
+    /// Get how long ago (in milliseconds) the last input activity happened.
+    GetIdleTime,
+    /// Get what input hardware is available.
+    GetCapabilities,
+    /// Read all inputs described by the capabilities.
+    ReadInputExt,
+    /// Set the calibration of the analog axis with the given index.
+    SetAxisCalibration(u8, AxisCalibration),
+    /// Accept only peers with a confirmed pairing.
+    SetPairingRequired(bool),
+    /// List peers waiting for the pairing to be confirmed, with their confirmation codes.
+    ListPairings,
+    /// Confirm the pairing with the peer and turn on encryption.
+    ConfirmPairing([u8; 6]),
+    /// Cancel the pairing with the peer.
+    RejectPairing([u8; 6]),
+    /// Set what to send in discovery beacons and which devices to accept.
+    SetBeacon(BeaconInfo),
+    /// Get the discovered device with the given index.
+    GetDiscovered(u8),
+    /// Get the peer with the given index.
+    GetPeer(u8),
+    /// Disconnect from the peer.
+    RemovePeer([u8; 6]),
+    /// Allow or block the device from becoming a peer.
+    SetAccess([u8; 6], Access),
+    /// Queue the message for the peer. Unlike `NetSend`, responds with the message ID.
+    Send([u8; 6], Vec<u8>),
+    /// Get the delivery status of the message with the given ID.
+    GetMessageStatus(u16),
+    /// Upload a chunk of a big message at the given offset. Zero offset starts a new message.
+    StageMessage(u16, Vec<u8>),
+    /// Send the uploaded big message to the peer.
+    SendStaged([u8; 6]),
+    /// Read a chunk of a received big message. Zero offset moves to the next message.
+    RecvLarge(u16),
+    /// Get how many frames from the peer were received, dropped as duplicates, and lost.
+    GetLinkStats([u8; 6]),
+    /// Open a reliable ordered stream with the peer.
+    OpenStream([u8; 6]),
+    CloseStream([u8; 6]),
+    /// Queue a segment into the stream with the peer.
+    StreamSend([u8; 6], Vec<u8>),
+    GetStreamStatus([u8; 6]),
+    /// Queue the message with the given delivery class
+    /// (0: unreliable, 1: latest wins, 2: reliable). Responds with the message ID.
+    SendClass([u8; 6], u8, Vec<u8>),
+    /// Set how outgoing messages are retried until the network is stopped.
+    SetRetryPolicy(RetryPolicy),
+    GetRetryPolicy,
+    /// Switch ESP-NOW to the given Wi-Fi channel. It's also announced in beacons.
+    ///
+    /// Without it, the device looks for beacons on all channels when the network
+    /// starts and follows the channel they announce.
+    SetChannel(u8),
+    /// Scan all channels and switch to the least congested one.
+    ///
+    /// Only allowed while the network is stopped.
+    PickChannel,
+}
+
+impl Request {
+    #[allow(clippy::too_many_lines)]
+    pub fn decode(payload: &[u8]) -> Result<Self> {
+        let mut r = Reader::new(payload);
+        if r.u8()? != EXT_TAG {
+            bail!("not an extension request");
+        }
+        let req = match r.u8()? {
+            1 => Self::ReadTouch,
+            2 => Self::SetTouchThreshold(Threshold {
+                press: r.u8()?,
+                release: r.u8()?,
+            }),
+            3 => Self::GetPadConfig,
+            4 => match PadConfig::from_bytes(r.array()?) {
+                Some(config) => Self::SetPadConfig(config),
+                None => bail!("invalid touchpad config"),
+            },
+            5 => Self::GetPadHealth,
+            6 => {
+                let flags = r.u8()?;
+                Self::SetStickConfig(StickConfig {
+                    eight_way: flags & 1 != 0,
+                    floating: flags & (1 << 1) != 0,
+                    dead_zone: r.u16()?,
+                    radius: r.u16()?,
+                })
+            }
+            7 => Self::SetFilter(read_filter(&mut r)?),
+            8 => Self::SetCombo(r.u8()?, read_combo(&mut r)?),
+            9 => Self::GetFlags,
+            10 => Self::ClearFlags(r.u8()?),
+            11 => Self::SetSampleRate(r.u16()?),
+            12 => Self::ReadFrame,
+            13 => Self::StartRecording,
+            14 => Self::StartPlayback,
+            15 => Self::StopReplay,
+            16 => Self::ReadRecording(r.u16()?),
+            17 => {
+                let offset = r.u16()?;
+                let mut events = Vec::new();
+                while !r.is_empty() {
+                    events.push(Event::from_bytes(r.array()?));
+                }
+                if events.len() > MAX_EVENTS {
+                    bail!("too many events");
+                }
+                Self::UploadScript(offset, events)
+            }
+            18 => Self::GetButtonMap,
+            19 => match ButtonMap::from_bytes(r.array()?) {
+                Some(map) => Self::SetButtonMap(map),
+                None => bail!("invalid button map"),
+            },
+            20 => Self::SetLatencyMode(r.u8()? != 0),
+            21 => Self::GetLatency,
+            22 => Self::SetPowerConfig(PowerConfig {
+                light_sleep: r.u8()? != 0,
+                idle: r.u16()?,
+            }),
+            23 => Self::GetPowerStatus,
+            24 => Self::DeepSleep,
+            25 => Self::SetIdleTimeout(r.u16()?),
+            26 => Self::GetIdleTime,
+            27 => Self::GetCapabilities,
+            28 => Self::ReadInputExt,
+            29 => Self::SetAxisCalibration(
+                r.u8()?,
+                AxisCalibration {
+                    min: r.u16()?,
+                    center: r.u16()?,
+                    max: r.u16()?,
+                    dead_zone: r.u16()?,
+                },
+            ),
+            30 => Self::SetPairingRequired(r.u8()? != 0),
+            31 => Self::ListPairings,
+            32 => Self::ConfirmPairing(r.array()?),
+            33 => Self::RejectPairing(r.array()?),
+            34 => Self::SetBeacon(BeaconInfo {
+                same_app: r.u8()? != 0,
+                lobby: r.u32()?,
+                name: r.str(MAX_NAME)?,
+                app: r.str(MAX_APP)?,
+            }),
+            35 => Self::GetDiscovered(r.u8()?),
+            36 => Self::GetPeer(r.u8()?),
+            37 => Self::RemovePeer(r.array()?),
+            38 => {
+                let addr = r.array()?;
+                let Ok(access) = r.u8()?.try_into() else {
+                    bail!("unknown access kind");
+                };
+                Self::SetAccess(addr, access)
+            }
+            39 => {
+                let addr = r.array()?;
+                let mut data = Vec::new();
+                while !r.is_empty() {
+                    data.push(r.u8()?);
+                }
+                Self::Send(addr, data)
+            }
+            40 => Self::GetMessageStatus(r.u16()?),
+            41 => {
+                let offset = r.u16()?;
+                let mut chunk = Vec::new();
+                while !r.is_empty() {
+                    chunk.push(r.u8()?);
+                }
+                Self::StageMessage(offset, chunk)
+            }
+            42 => Self::SendStaged(r.array()?),
+            43 => Self::RecvLarge(r.u16()?),
+            44 => Self::GetLinkStats(r.array()?),
+            45 => Self::OpenStream(r.array()?),
+            46 => Self::CloseStream(r.array()?),
+            47 => {
+                let addr = r.array()?;
+                let mut data = Vec::new();
+                while !r.is_empty() {
+                    data.push(r.u8()?);
+                }
+                Self::StreamSend(addr, data)
+            }
+            48 => Self::GetStreamStatus(r.array()?),
+            49 => {
+                let addr = r.array()?;
+                let class = r.u8()?;
+                let mut data = Vec::new();
+                while !r.is_empty() {
+                    data.push(r.u8()?);
+                }
+                Self::SendClass(addr, class, data)
+            }
+            50 => Self::SetRetryPolicy(RetryPolicy {
+                max_attempts: r.u8()?,
+                base_delay: r.u16()?,
+                max_delay: r.u16()?,
+                deadline: r.u16()?,
+            }),
+            51 => Self::GetRetryPolicy,
+            52 => Self::SetChannel(r.u8()?),
+            53 => Self::PickChannel,
+            kind => bail!("unknown extension request: {kind}"),
+        };
+        r.finish()?;
+        Ok(req)
+    }
+}
+
+fn read_filter(r: &mut Reader<'_>) -> Result<FilterConfig> {
+    let kind = r.u8()?;
+    let a = r.u16()?;
+    let b = r.u16()?;
+    let config = match kind {
+        0 => FilterConfig::Off,
+        1 => {
+            let Ok(window) = u8::try_from(a) else {
+                bail!("filter window is too big: {a}");
+            };
+            if window == 0 {
+                bail!("filter window must not be zero");
+            }
+            FilterConfig::MovingAverage { window }
+        }
+        2 => FilterConfig::Exponential { alpha: a },
+        3 => FilterConfig::OneEuro {
+            min_cutoff: a,
+            beta: b,
+        },
+        _ => bail!("unknown filter: {kind}"),
+    };
+    Ok(config)
+}
+
+/// Read a combo. Returns None if the combo has no buttons, meaning the slot must be cleared.
+fn read_combo(r: &mut Reader<'_>) -> Result<Option<Combo>> {
+    let buttons = r.u8()?;
+    let hold = r.u16()?;
+    let at_boot = r.u8()? != 0;
+    let Ok(action) = r.u8()?.try_into() else {
+        bail!("unknown combo action");
+    };
+    if buttons == 0 {
+        return Ok(None);
+    }
+    Ok(Some(Combo {
+        buttons,
+        hold,
+        at_boot,
+        action,
+    }))
+}
+
+pub enum Response {
+    Touch(Touch, StickState),
+    TouchThresholdSet,
+    PadConfig(PadConfig),
+    PadConfigSet,
+    PadHealth(PadHealth),
+    StickConfigSet,
+    FilterSet,
+    ComboSet,
+    Flags(u8),
+    FlagsCleared,
+    SampleRateSet,
+    /// The frame summary, how long ago (in microseconds) the latest sample
+    /// was captured, and how long ago the latest input edge was captured.
+    Frame(FrameSummary, u32, u32),
+    RecordingStarted,
+    PlaybackStarted,
+    ReplayStopped,
+    /// The total number of recorded events, how many input changes didn't fit
+    /// into the recording (if not zero, the recording is truncated),
+    /// and a chunk of the events.
+    Recording(u16, u16, Vec<Event>),
+    ScriptUploaded,
+    ButtonMap(ButtonMap),
+    ButtonMapSet,
+    LatencyModeSet,
+    Latency(LatencyStats),
+    PowerConfigSet,
+    /// The power config, how many times the chip entered light sleep, and the last wake reason.
+    PowerStatus(PowerConfig, u32, WakeReason),
+    DeepSleepScheduled,
+    IdleTimeoutSet,
+    IdleTime(u32),
+    Capabilities(Capabilities),
+    InputExt(InputState),
+    AxisCalibrationSet,
+    PairingRequiredSet,
+    Pairings(Vec<Pending>),
+    PairingConfirmed,
+    PairingRejected,
+    BeaconSet,
+    /// The number of discovered devices and the requested device, if the index is valid.
+    Discovered(u8, Option<([u8; 6], Beacon)>),
+    /// The number of peers and the requested peer, if the index is valid.
+    Peer(u8, Option<PeerMeta>),
+    PeerRemoved,
+    AccessSet,
+    /// The ID of the queued message.
+    Queued(u16),
+    /// The delivery status of the message (0: unknown, 1: sending, 2: delivered, 3: failed)
+    /// and the number of failed attempts.
+    MessageStatus(u8, u8),
+    MessageStaged,
+    /// The sender, the total size of the message, and the chunk of it.
+    LargeMessage([u8; 6], u16, Vec<u8>),
+    LinkStats(LinkStats),
+    StreamOpened,
+    StreamClosed,
+    StreamQueued,
+    /// The state of the stream, if it's open.
+    StreamStatus(Option<StreamStatus>),
+    RetryPolicySet,
+    RetryPolicy(RetryPolicy),
+    ChannelSet,
+    /// The channel picked by the scan.
+    Channel(u8),
+}
+
+impl Response {
+    #[allow(clippy::too_many_lines)]
+    pub fn encode_buf<'a>(&self, buf: &'a mut [u8]) -> Result<&'a [u8]> {
+        let mut w = Writer::new(buf);
+        w.u8(EXT_TAG)?;
+        match self {
+            Self::Touch(touch, stick) => {
+                w.u8(1)?;
+                w.u16(touch.x)?;
+                w.u16(touch.y)?;
+                w.u8(touch.z)?;
+                w.u8(touch.state.into())?;
+                w.u8(stick.dpad)?;
+                w.i16(stick.x)?;
+                w.i16(stick.y)?;
+            }
+            Self::TouchThresholdSet => w.u8(2)?,
+            Self::PadConfig(config) => {
+                w.u8(3)?;
+                w.bytes(&config.to_bytes())?;
+            }
+            Self::PadConfigSet => w.u8(4)?,
+            Self::PadHealth(health) => {
+                w.u8(5)?;
+                w.u8(u8::from(health.is_ready()))?;
+                w.u16(health.errors)?;
+                w.u16(health.inits)?;
+            }
+            Self::StickConfigSet => w.u8(6)?,
+            Self::FilterSet => w.u8(7)?,
+            Self::ComboSet => w.u8(8)?,
+            Self::Flags(flags) => {
+                w.u8(9)?;
+                w.u8(*flags)?;
+            }
+            Self::FlagsCleared => w.u8(10)?,
+            Self::SampleRateSet => w.u8(11)?,
+            Self::Frame(frame, age, edge_age) => {
+                w.u8(12)?;
+                write_frame(&mut w, frame)?;
+                w.u32(*age)?;
+                w.u32(*edge_age)?;
+            }
+            Self::RecordingStarted => w.u8(13)?,
+            Self::PlaybackStarted => w.u8(14)?,
+            Self::ReplayStopped => w.u8(15)?,
+            Self::Recording(total, dropped, events) => {
+                w.u8(16)?;
+                w.u16(*total)?;
+                w.u16(*dropped)?;
+                for event in events {
+                    w.bytes(&event.to_bytes())?;
+                }
+            }
+            Self::ScriptUploaded => w.u8(17)?,
+            Self::ButtonMap(map) => {
+                w.u8(18)?;
+                w.bytes(&map.to_bytes())?;
+            }
+            Self::ButtonMapSet => w.u8(19)?,
+            Self::LatencyModeSet => w.u8(20)?,
+            Self::Latency(stats) => {
+                w.u8(21)?;
+                w.u16(stats.count)?;
+                w.u32(stats.min)?;
+                w.u32(stats.max)?;
+                w.u32(stats.mean)?;
+                for bucket in &stats.histogram {
+                    w.u16(*bucket)?;
+                }
+            }
+            Self::PowerConfigSet => w.u8(22)?,
+            Self::PowerStatus(config, sleeps, wake_reason) => {
+                w.u8(23)?;
+                w.u8(u8::from(config.light_sleep))?;
+                w.u16(config.idle)?;
+                w.u32(*sleeps)?;
+                w.u8((*wake_reason).into())?;
+            }
+            Self::DeepSleepScheduled => w.u8(24)?,
+            Self::IdleTimeoutSet => w.u8(25)?,
+            Self::IdleTime(time) => {
+                w.u8(26)?;
+                w.u32(*time)?;
+            }
+            Self::Capabilities(caps) => {
+                w.u8(27)?;
+                w.u8(INPUT_FORMAT)?;
+                w.u8(caps.buttons)?;
+                w.u8(caps.axes)?;
+                w.u8(u8::from(caps.touchpad))?;
+            }
+            Self::InputExt(input) => {
+                w.u8(28)?;
+                write_input(&mut w, input)?;
+            }
+            Self::AxisCalibrationSet => w.u8(29)?,
+            Self::PairingRequiredSet => w.u8(30)?,
+            Self::Pairings(pairings) => {
+                w.u8(31)?;
+                for pairing in pairings {
+                    w.bytes(&pairing.addr)?;
+                    w.u32(pairing.keys.code)?;
+                }
+            }
+            Self::PairingConfirmed => w.u8(32)?,
+            Self::PairingRejected => w.u8(33)?,
+            Self::BeaconSet => w.u8(34)?,
+            Self::Discovered(total, device) => {
+                w.u8(35)?;
+                w.u8(*total)?;
+                if let Some((addr, beacon)) = device {
+                    w.bytes(addr)?;
+                    write_beacon(&mut w, beacon)?;
+                }
+            }
+            Self::Peer(total, peer) => {
+                w.u8(36)?;
+                w.u8(*total)?;
+                if let Some(peer) = peer {
+                    w.bytes(&peer.addr)?;
+                    w.u32(u32::try_from(peer.first_seen).unwrap_or(u32::MAX))?;
+                    w.u32(u32::try_from(peer.last_seen).unwrap_or(u32::MAX))?;
+                    w.bytes(&peer.rssi.to_le_bytes())?;
+                    w.u8(u8::from(peer.encrypted))?;
+                }
+            }
+            Self::PeerRemoved => w.u8(37)?,
+            Self::AccessSet => w.u8(38)?,
+            Self::Queued(id) => {
+                w.u8(39)?;
+                w.u16(*id)?;
+            }
+            Self::MessageStatus(status, attempts) => {
+                w.u8(40)?;
+                w.u8(*status)?;
+                w.u8(*attempts)?;
+            }
+            Self::MessageStaged => w.u8(41)?,
+            Self::LargeMessage(addr, total, chunk) => {
+                w.u8(43)?;
+                w.bytes(addr)?;
+                w.u16(*total)?;
+                w.bytes(chunk)?;
+            }
+            Self::LinkStats(stats) => {
+                w.u8(44)?;
+                w.u32(stats.received)?;
+                w.u32(stats.duplicates)?;
+                w.u32(stats.lost)?;
+            }
+            Self::StreamOpened => w.u8(45)?,
+            Self::StreamClosed => w.u8(46)?,
+            Self::StreamQueued => w.u8(47)?,
+            Self::StreamStatus(status) => {
+                w.u8(48)?;
+                w.u8(u8::from(status.is_some()))?;
+                if let Some(status) = status {
+                    w.u8(status.unacked)?;
+                    w.u32(status.retransmits)?;
+                    w.u8(u8::from(status.failed))?;
+                }
+            }
+            Self::RetryPolicySet => w.u8(50)?,
+            Self::RetryPolicy(policy) => {
+                w.u8(51)?;
+                w.u8(policy.max_attempts)?;
+                w.u16(policy.base_delay)?;
+                w.u16(policy.max_delay)?;
+                w.u16(policy.deadline)?;
+            }
+            Self::ChannelSet => w.u8(52)?,
+            Self::Channel(channel) => {
+                w.u8(53)?;
+                w.u8(*channel)?;
+            }
+        }
+        Ok(w.finish())
+    }
+}
+
+fn write_frame(w: &mut Writer<'_>, frame: &FrameSummary) -> Result<()> {
+    w.u8(frame.buttons)?;
+    w.u8(frame.held)?;
+    w.bytes(&frame.presses)?;
+    let flags = u8::from(frame.pos.is_some())
+        | u8::from(frame.touch_started) << 1
+        | u8::from(frame.touch_ended) << 2;
+    w.u8(flags)?;
+    let (x, y) = frame.pos.unwrap_or_default();
+    w.u16(x)?;
+    w.u16(y)?;
+    w.u32(frame.path)?;
+    w.u16(frame.samples)
+}
+
+fn write_beacon(w: &mut Writer<'_>, beacon: &Beacon) -> Result<()> {
+    w.u8(beacon.protocol)?;
+    w.bytes(&<[u8; 3]>::from(beacon.firmware))?;
+    w.u32(beacon.lobby)?;
+    w.str(&beacon.name)?;
+    w.str(&beacon.app)?;
+    w.u8(beacon.channel)
+}
+
+fn write_input(w: &mut Writer<'_>, input: &InputState) -> Result<()> {
+    // Buttons are sent as the count followed by just enough bytes
+    // to fit the bitmask, so that the format doesn't depend on MAX_BUTTONS.
+    w.u8(input.count)?;
+    let bytes = input.buttons.to_le_bytes();
+    w.bytes(&bytes[..usize::from(input.count).div_ceil(8)])?;
+    let axes = u8::try_from(input.axes.len()).unwrap_or_default();
+    w.u8(axes)?;
+    for axis in &input.axes {
+        w.i16(*axis)?;
+    }
+    let (x, y) = input.pos.unwrap_or_default();
+    w.u8(u8::from(input.pos.is_some()))?;
+    w.u16(x)?;
+    w.u16(y)?;
+    w.u32(input.edge_age)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    const fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        let Some(&b) = self.buf.get(self.pos) else {
+            bail!("unexpected end of request");
+        };
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut res = [0u8; N];
+        for b in &mut res {
+            *b = self.u8()?;
+        }
+        Ok(res)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    /// Read a length-prefixed UTF-8 string.
+    fn str(&mut self, max: usize) -> Result<String> {
+        let len = usize::from(self.u8()?);
+        if len > max {
+            bail!("string is too long");
+        }
+        let Some(raw) = self.buf.get(self.pos..self.pos + len) else {
+            bail!("unexpected end of request");
+        };
+        self.pos += len;
+        let Ok(s) = core::str::from_utf8(raw) else {
+            bail!("invalid UTF-8 string");
+        };
+        Ok(String::from(s))
+    }
+
+    const fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    /// Make sure there are no unread bytes left.
+    fn finish(&self) -> Result<()> {
+        if self.pos != self.buf.len() {
+            bail!("unexpected trailing bytes in request");
+        }
+        Ok(())
+    }
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    const fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn u8(&mut self, v: u8) -> Result<()> {
+        let Some(b) = self.buf.get_mut(self.pos) else {
+            bail!("response is too big");
+        };
+        *b = v;
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn u16(&mut self, v: u16) -> Result<()> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn u32(&mut self, v: u32) -> Result<()> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn i16(&mut self, v: i16) -> Result<()> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn bytes(&mut self, v: &[u8]) -> Result<()> {
+        for b in v {
+            self.u8(*b)?;
+        }
+        Ok(())
+    }
+
+    /// Write a length-prefixed string.
+    fn str(&mut self, v: &str) -> Result<()> {
+        let Ok(len) = u8::try_from(v.len()) else {
+            bail!("string is too long");
+        };
+        self.u8(len)?;
+        self.bytes(v.as_bytes())
+    }
+
+    fn finish(self) -> &'a [u8] {
+        &self.buf[..self.pos]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_moving_average() {
+        let req = Request::decode(&[EXT_TAG, 7, 1, 8, 0, 0, 0]).unwrap();
+        assert!(matches!(
+            req,
+            Request::SetFilter(FilterConfig::MovingAverage { window: 8 })
+        ));
+        // 0x0108 must not be truncated to a valid window of 8.
+        assert!(Request::decode(&[EXT_TAG, 7, 1, 8, 1, 0, 0]).is_err());
+        assert!(Request::decode(&[EXT_TAG, 7, 1, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn encode_input_ext() {
+        let input = InputState {
+            count: 9,
+            buttons: 0x1_0003,
+            axes: alloc::vec![-1000, 1000],
+            pos: Some((300, 400)),
+            edge_age: 70_000,
+        };
+        let mut buf = [0; 64];
+        let raw = Response::InputExt(input).encode_buf(&mut buf).unwrap();
+        let expected = [
+            EXT_TAG, 28, // header
+            9, 0x03, 0x00, // buttons
+            2, 0x18, 0xFC, 0xE8, 0x03, // axes
+            1, 0x2C, 0x01, 0x90, 0x01, // touchpad
+            0x70, 0x11, 0x01, 0x00, // edge age
+        ];
+        assert_eq!(raw, expected);
+    }
+}
@@ -0,0 +1,243 @@
+//! Recording and deterministic playback of input.
+//!
+//! When recording, every change of buttons or touchpad position is stored
+//! with a timestamp relative to the start of the recording. When playing,
+//! the uploaded script replaces the real input. After the last event,
+//! its input is held until the playback is stopped.
+use alloc::vec::Vec;
+
+/// Max number of events in a recording or a script.
+pub const MAX_EVENTS: usize = 1024;
+
+/// A single input change.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    /// Milliseconds since the start of the recording or playback.
+    pub time: u32,
+    pub buttons: u8,
+    pub pos: Option<(u16, u16)>,
+}
+
+impl Event {
+    pub const SIZE: usize = 9;
+
+    /// Serialize the event. The touch flag is stored in the highest bit of buttons.
+    #[must_use]
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let (x, y) = self.pos.unwrap_or_default();
+        let [t0, t1, t2, t3] = self.time.to_le_bytes();
+        let [x0, x1] = x.to_le_bytes();
+        let [y0, y1] = y.to_le_bytes();
+        let buttons = self.buttons | u8::from(self.pos.is_some()) << 7;
+        [t0, t1, t2, t3, buttons, x0, x1, y0, y1]
+    }
+
+    #[must_use]
+    pub const fn from_bytes(raw: [u8; Self::SIZE]) -> Self {
+        let [t0, t1, t2, t3, buttons, x0, x1, y0, y1] = raw;
+        let pos = if buttons & (1 << 7) == 0 {
+            None
+        } else {
+            Some((u16::from_le_bytes([x0, x1]), u16::from_le_bytes([y0, y1])))
+        };
+        Self {
+            time: u32::from_le_bytes([t0, t1, t2, t3]),
+            buttons: buttons & 0x7F,
+            pos,
+        }
+    }
+}
+
+enum Mode {
+    Idle,
+    Recording { start: u64 },
+    Playing { start: u64, cursor: usize },
+}
+
+pub struct Replay {
+    mode: Mode,
+    /// The last recording.
+    recorded: Vec<Event>,
+    /// How many input changes didn't fit into the recording.
+    dropped: u16,
+    /// The uploaded script for playback.
+    script: Vec<Event>,
+}
+
+impl Replay {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            mode: Mode::Idle,
+            recorded: Vec::new(),
+            dropped: 0,
+            script: Vec::new(),
+        }
+    }
+
+    /// Start a new recording, discarding the previous one.
+    pub fn record(&mut self, now: u64) {
+        self.recorded.clear();
+        self.dropped = 0;
+        self.mode = Mode::Recording { start: now };
+    }
+
+    /// Start playing the uploaded script.
+    pub const fn play(&mut self, now: u64) {
+        self.mode = Mode::Playing {
+            start: now,
+            cursor: 0,
+        };
+    }
+
+    /// Stop recording or playback.
+    pub const fn stop(&mut self) {
+        self.mode = Mode::Idle;
+    }
+
+    /// Recorded events starting from the given index.
+    #[must_use]
+    pub fn recorded(&self, offset: usize) -> &[Event] {
+        self.recorded.get(offset..).unwrap_or_default()
+    }
+
+    /// The number of recorded events.
+    #[must_use]
+    pub const fn total(&self) -> usize {
+        self.recorded.len()
+    }
+
+    /// How many input changes were lost because the recording is full.
+    ///
+    /// If not zero, the recording is truncated.
+    #[must_use]
+    pub const fn dropped(&self) -> u16 {
+        self.dropped
+    }
+
+    /// Add events to the end of the script.
+    ///
+    /// If the offset is zero, the old script is discarded. Otherwise,
+    /// it must be equal to the current script length, so that lost or
+    /// repeated chunks are detected. Returns false if the offset is wrong
+    /// or the script is too long.
+    pub fn upload(&mut self, offset: usize, events: &[Event]) -> bool {
+        if offset == 0 {
+            self.script.clear();
+        }
+        if offset != self.script.len() || offset + events.len() > MAX_EVENTS {
+            return false;
+        }
+        self.script.extend_from_slice(events);
+        true
+    }
+
+    /// Process the real input.
+    ///
+    /// When recording, stores the input if it has changed. When playing,
+    /// returns the scripted input instead of the real one.
+    pub fn process(
+        &mut self,
+        buttons: u8,
+        pos: Option<(u16, u16)>,
+        now: u64,
+    ) -> (u8, Option<(u16, u16)>) {
+        match &mut self.mode {
+            Mode::Idle => (buttons, pos),
+            Mode::Recording { start } => {
+                let time = u32::try_from(now - *start).unwrap_or(u32::MAX);
+                let event = Event { time, buttons, pos };
+                let changed = self
+                    .recorded
+                    .last()
+                    .is_none_or(|last| last.buttons != buttons || last.pos != pos);
+                if changed {
+                    if self.recorded.len() >= MAX_EVENTS {
+                        self.dropped = self.dropped.saturating_add(1);
+                    } else {
+                        self.recorded.push(event);
+                    }
+                }
+                (buttons, pos)
+            }
+            Mode::Playing { start, cursor } => {
+                let time = now - *start;
+                while let Some(next) = self.script.get(*cursor + 1) {
+                    if u64::from(next.time) > time {
+                        break;
+                    }
+                    *cursor += 1;
+                }
+                match self.script.get(*cursor) {
+                    // The first event is in the future: no input yet.
+                    Some(event) if u64::from(event.time) > time => (0, None),
+                    Some(event) => (event.buttons, event.pos),
+                    None => (0, None),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_only_changes() {
+        let mut r = Replay::new();
+        r.record(100);
+        r.process(0, None, 100);
+        r.process(0, None, 110);
+        r.process(1, Some((5, 6)), 120);
+        r.stop();
+        r.process(2, None, 130);
+        let events = r.recorded(0);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].time, 20);
+        assert!(Event::from_bytes(events[1].to_bytes()) == events[1]);
+        assert_eq!(r.recorded(1).len(), 1);
+        assert!(r.recorded(5).is_empty());
+    }
+
+    #[test]
+    fn count_dropped_when_full() {
+        let mut r = Replay::new();
+        r.record(0);
+        for i in 0..MAX_EVENTS as u64 + 10 {
+            let buttons = u8::try_from(i % 100).unwrap();
+            r.process(buttons, None, i);
+        }
+        assert_eq!(r.total(), MAX_EVENTS);
+        assert_eq!(r.dropped(), 10);
+        r.record(0);
+        assert_eq!(r.total(), 0);
+        assert_eq!(r.dropped(), 0);
+    }
+
+    #[test]
+    fn play_script() {
+        let mut r = Replay::new();
+        let script = [
+            Event {
+                time: 10,
+                buttons: 1,
+                pos: None,
+            },
+            Event {
+                time: 20,
+                buttons: 2,
+                pos: Some((1, 2)),
+            },
+        ];
+        assert!(r.upload(0, &script[..1]));
+        assert!(!r.upload(2, &script[1..]));
+        assert!(r.upload(1, &script[1..]));
+        r.play(1000);
+        assert_eq!(r.process(4, None, 1005), (0, None));
+        assert_eq!(r.process(4, None, 1015), (1, None));
+        assert_eq!(r.process(4, None, 1500), (2, Some((1, 2))));
+        r.stop();
+        assert_eq!(r.process(4, None, 1600), (4, None));
+    }
+}
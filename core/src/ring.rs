@@ -0,0 +1,280 @@
+//! Fixed-capacity containers that never allocate.
+//!
+//! They are used for the state shared with the esp-now callbacks,
+//! so that memory use is known upfront and the allocator is never called
+//! from a callback or inside a critical section.
+
+/// A fixed-capacity double-ended queue.
+pub struct Ring<T, const N: usize> {
+    items: [Option<T>; N],
+    /// The index of the first item.
+    head: usize,
+    len: usize,
+}
+
+impl<T, const N: usize> Ring<T, N> {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            items: [const { None }; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    #[must_use]
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[must_use]
+    pub const fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Add the item to the end. If the ring is full, the item is returned back.
+    pub fn push_back(&mut self, item: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(item);
+        }
+        self.items[(self.head + self.len) % N] = Some(item);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Add the item to the start. If the ring is full, the item is returned back.
+    pub fn push_front(&mut self, item: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(item);
+        }
+        self.head = (self.head + N - 1) % N;
+        self.items[self.head] = Some(item);
+        self.len += 1;
+        Ok(())
+    }
+
+    pub const fn pop_front(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        let item = self.items[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        item
+    }
+
+    #[must_use]
+    pub const fn front(&self) -> Option<&T> {
+        self.get(0)
+    }
+
+    #[must_use]
+    pub const fn get(&self, index: usize) -> Option<&T> {
+        if index >= self.len {
+            return None;
+        }
+        self.items[(self.head + index) % N].as_ref()
+    }
+
+    pub const fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        if index >= self.len {
+            return None;
+        }
+        self.items[(self.head + index) % N].as_mut()
+    }
+
+    /// Remove the item at the given index, keeping the order of the rest.
+    pub fn remove(&mut self, index: usize) -> Option<T> {
+        let mut removed = None;
+        for i in 0..self.len {
+            let item = self.pop_front()?;
+            if i == index {
+                removed = Some(item);
+            } else {
+                _ = self.push_back(item);
+            }
+        }
+        removed
+    }
+
+    /// Keep only the items for which the function returns true.
+    pub fn retain(&mut self, mut f: impl FnMut(&T) -> bool) {
+        for _ in 0..self.len {
+            let Some(item) = self.pop_front() else {
+                return;
+            };
+            if f(&item) {
+                // Never fails: an item was just popped.
+                _ = self.push_back(item);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        while self.pop_front().is_some() {}
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        let (start, end) = self.items.split_at(self.head);
+        end.iter().chain(start).filter_map(Option::as_ref)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        let (start, end) = self.items.split_at_mut(self.head);
+        end.iter_mut().chain(start).filter_map(Option::as_mut)
+    }
+}
+
+/// Preallocated buffers for packets of up to `SIZE` bytes.
+pub struct Pool<const N: usize, const SIZE: usize> {
+    buffers: [[u8; SIZE]; N],
+    /// The size of the packet in each buffer. None if the buffer is free.
+    sizes: [Option<usize>; N],
+}
+
+impl<const N: usize, const SIZE: usize> Pool<N, SIZE> {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            buffers: [[0; SIZE]; N],
+            sizes: [None; N],
+        }
+    }
+
+    /// Copy the packet into a free buffer and return the buffer index.
+    ///
+    /// Returns None if the packet is too big or there are no free buffers.
+    pub fn alloc(&mut self, data: &[u8]) -> Option<usize> {
+        if data.len() > SIZE {
+            return None;
+        }
+        let index = self.sizes.iter().position(Option::is_none)?;
+        self.buffers[index][..data.len()].copy_from_slice(data);
+        self.sizes[index] = Some(data.len());
+        Some(index)
+    }
+
+    /// Get the packet in the buffer. Empty if the buffer is free.
+    #[must_use]
+    pub fn get(&self, index: usize) -> &[u8] {
+        let size = self.sizes.get(index).copied().flatten().unwrap_or(0);
+        &self.buffers[index % N][..size]
+    }
+
+    pub fn free(&mut self, index: usize) {
+        if let Some(size) = self.sizes.get_mut(index) {
+            *size = None;
+        }
+    }
+
+    /// The number of free buffers.
+    #[must_use]
+    pub fn available(&self) -> usize {
+        self.sizes.iter().filter(|size| size.is_none()).count()
+    }
+
+    pub const fn clear(&mut self) {
+        self.sizes = [None; N];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn items<const N: usize>(ring: &Ring<u8, N>) -> Vec<u8> {
+        ring.iter().copied().collect()
+    }
+
+    #[test]
+    fn ring_full() {
+        let mut ring: Ring<u8, 3> = Ring::new();
+        assert!(ring.push_back(1).is_ok());
+        assert!(ring.push_back(2).is_ok());
+        assert!(ring.push_front(0).is_ok());
+        assert!(ring.is_full());
+        assert_eq!(ring.push_back(9), Err(9));
+        assert_eq!(ring.push_front(9), Err(9));
+        assert_eq!(items(&ring), [0, 1, 2]);
+    }
+
+    #[test]
+    fn ring_wrap_around() {
+        let mut ring: Ring<u8, 4> = Ring::new();
+        for i in 0..4 {
+            ring.push_back(i).unwrap();
+        }
+        // Move the head so that the items wrap around the end of the buffer.
+        assert_eq!(ring.pop_front(), Some(0));
+        assert_eq!(ring.pop_front(), Some(1));
+        ring.push_back(4).unwrap();
+        ring.push_back(5).unwrap();
+        assert_eq!(items(&ring), [2, 3, 4, 5]);
+        assert_eq!(ring.get(3), Some(&5));
+        assert_eq!(ring.get(4), None);
+
+        assert_eq!(ring.remove(1), Some(3));
+        assert_eq!(items(&ring), [2, 4, 5]);
+        assert_eq!(ring.remove(3), None);
+        assert_eq!(items(&ring), [2, 4, 5]);
+
+        ring.push_front(1).unwrap();
+        ring.retain(|x| x % 2 == 1);
+        assert_eq!(items(&ring), [1, 5]);
+        for x in ring.iter_mut() {
+            *x += 1;
+        }
+        assert_eq!(items(&ring), [2, 6]);
+        assert_eq!(ring.front(), Some(&2));
+    }
+
+    #[test]
+    fn ring_clear() {
+        let mut ring: Ring<u8, 2> = Ring::new();
+        ring.push_back(1).unwrap();
+        ring.push_back(2).unwrap();
+        ring.clear();
+        assert!(ring.is_empty());
+        assert_eq!(ring.pop_front(), None);
+        assert_eq!(ring.front(), None);
+    }
+
+    #[test]
+    fn pool_exhausted() {
+        let mut pool: Pool<3, 4> = Pool::new();
+        let a = pool.alloc(&[1]).unwrap();
+        let b = pool.alloc(&[2, 3]).unwrap();
+        let c = pool.alloc(&[4, 5, 6, 7]).unwrap();
+        assert_eq!(pool.available(), 0);
+        assert_eq!(pool.alloc(&[8]), None);
+        assert_eq!(pool.get(a), &[1]);
+        assert_eq!(pool.get(b), &[2, 3]);
+        assert_eq!(pool.get(c), &[4, 5, 6, 7]);
+
+        pool.free(b);
+        assert_eq!(pool.available(), 1);
+        assert_eq!(pool.get(b), &[] as &[u8]);
+        assert_eq!(pool.alloc(&[9]), Some(b));
+        assert_eq!(pool.get(b), &[9]);
+
+        pool.clear();
+        assert_eq!(pool.available(), 3);
+    }
+
+    #[test]
+    fn pool_oversized() {
+        let mut pool: Pool<2, 4> = Pool::new();
+        assert_eq!(pool.alloc(&[0; 5]), None);
+        assert_eq!(pool.available(), 2);
+        assert!(pool.alloc(&[0; 4]).is_some());
+        // Out of range indices are ignored.
+        pool.free(10);
+        assert_eq!(pool.get(10), &[] as &[u8]);
+    }
+}
@@ -0,0 +1,120 @@
+//! Fixed-rate background input sampling.
+//!
+//! Instead of reading input only when the main chip asks for it,
+//! the IO chip samples it at a fixed rate and aggregates everything
+//! that happened since the last read. So, short presses and taps
+//! that start and end between two frames are not lost.
+
+/// Number of physical buttons.
+pub const BUTTONS: usize = 5;
+
+/// Everything that happened with input since the last read.
+#[derive(Clone, Copy, Default)]
+pub struct FrameSummary {
+    /// Buttons pressed in the latest sample.
+    pub buttons: u8,
+    /// Buttons that were pressed at any point during the frame.
+    pub held: u8,
+    /// How many times each button was pressed during the frame.
+    pub presses: [u8; BUTTONS],
+    /// The touchpad position in the latest sample.
+    pub pos: Option<(u16, u16)>,
+    /// If a touch has started during the frame.
+    pub touch_started: bool,
+    /// If a touch has ended during the frame.
+    pub touch_ended: bool,
+    /// The total distance (in pad units) the finger moved during the frame.
+    pub path: u32,
+    /// How many samples the summary is made of.
+    pub samples: u16,
+}
+
+#[derive(Default)]
+pub struct Aggregator {
+    summary: FrameSummary,
+    prev_buttons: u8,
+    prev_pos: Option<(u16, u16)>,
+}
+
+impl Aggregator {
+    /// Add a new input sample into the current frame.
+    pub fn add(&mut self, buttons: u8, pos: Option<(u16, u16)>) {
+        let s = &mut self.summary;
+        s.samples = s.samples.saturating_add(1);
+        s.buttons = buttons;
+        s.held |= buttons;
+        let pressed = buttons & !self.prev_buttons;
+        for (i, count) in s.presses.iter_mut().enumerate() {
+            if pressed & (1 << i) != 0 {
+                *count = count.saturating_add(1);
+            }
+        }
+        match (self.prev_pos, pos) {
+            (None, Some(_)) => s.touch_started = true,
+            (Some(_), None) => s.touch_ended = true,
+            (Some(a), Some(b)) => s.path = s.path.saturating_add(distance(a, b)),
+            (None, None) => {}
+        }
+        s.pos = pos;
+        self.prev_buttons = buttons;
+        self.prev_pos = pos;
+    }
+
+    /// Get the summary of the current frame and start a new one.
+    pub fn take(&mut self) -> FrameSummary {
+        let summary = self.summary;
+        self.summary = FrameSummary {
+            buttons: self.prev_buttons,
+            pos: self.prev_pos,
+            ..FrameSummary::default()
+        };
+        summary
+    }
+}
+
+/// Decides when it's time to take the next sample.
+#[derive(Default)]
+pub struct Sampler {
+    /// Time between samples in microseconds. Zero if sampling is disabled.
+    period: u64,
+    /// When the next sample is due.
+    next_at: u64,
+}
+
+impl Sampler {
+    /// Set the sampling rate in Hz. Zero disables sampling.
+    pub fn set_rate(&mut self, rate: u16) {
+        self.period = if rate == 0 {
+            0
+        } else {
+            1_000_000 / u64::from(rate)
+        };
+        self.next_at = 0;
+    }
+
+    /// Check if the background sampling is enabled.
+    #[must_use]
+    pub const fn is_enabled(&self) -> bool {
+        self.period != 0
+    }
+
+    /// Check if it's time to take a sample at the given time (in microseconds).
+    pub const fn due(&mut self, now: u64) -> bool {
+        if self.period == 0 || now < self.next_at {
+            return false;
+        }
+        // If we are late for more than one period, skip the missed samples
+        // instead of taking a burst of them.
+        self.next_at += self.period;
+        if self.next_at <= now {
+            self.next_at = now + self.period;
+        }
+        true
+    }
+}
+
+fn distance(a: (u16, u16), b: (u16, u16)) -> u32 {
+    let dx = u32::from(a.0.abs_diff(b.0));
+    let dy = u32::from(a.1.abs_diff(b.1));
+    (dx * dx + dy * dy).isqrt()
+}
@@ -0,0 +1,285 @@
+//! Per-peer sequence numbers and duplicate suppression.
+//!
+//! Every data frame is prefixed with a sequence number, counted separately
+//! for each peer. When an ack is lost, the sender retries the frame that
+//! the peer has already received. The retry has the same sequence number,
+//! so the receiver can recognize and drop it. Skipped sequence numbers
+//! are counted as lost frames.
+//!
+//! Sequence numbers start from zero every time the network is started,
+//! so the stamp also includes a random epoch picked on start. When the epoch
+//! of a peer changes, the peer has restarted and its window starts over.
+//! Otherwise, the first frames after the restart would look like retries
+//! of old frames and be dropped.
+use alloc::vec::Vec;
+
+/// Max number of peers to track sequence numbers for.
+pub const MAX_TRACKED: usize = 20;
+/// The size of the epoch and sequence number prefix.
+pub const SEQ_SIZE: usize = 4;
+/// How many recent sequence numbers are remembered to detect duplicates.
+const WINDOW: u16 = 64;
+
+type Addr = [u8; 6];
+
+/// The position of a frame in the stream of frames from a peer.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Stamp {
+    /// Random for each start of the network on the sender.
+    pub epoch: u16,
+    pub seq: u16,
+}
+
+/// Prefix the frame with the stamp.
+#[must_use]
+pub fn stamp(stamp: Stamp, frame: &[u8]) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(SEQ_SIZE + frame.len());
+    raw.extend_from_slice(&stamp.epoch.to_le_bytes());
+    raw.extend_from_slice(&stamp.seq.to_le_bytes());
+    raw.extend_from_slice(frame);
+    raw
+}
+
+/// Split the raw payload into the stamp and the frame.
+#[must_use]
+pub fn unstamp(raw: &[u8]) -> Option<(Stamp, &[u8])> {
+    let (epoch, raw) = raw.split_first_chunk()?;
+    let (seq, frame) = raw.split_first_chunk()?;
+    let stamp = Stamp {
+        epoch: u16::from_le_bytes(*epoch),
+        seq: u16::from_le_bytes(*seq),
+    };
+    Some((stamp, frame))
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct LinkStats {
+    /// How many unique frames were received.
+    pub received: u32,
+    /// How many duplicate frames were dropped.
+    pub duplicates: u32,
+    /// How many frames were skipped and have not arrived (yet).
+    pub lost: u32,
+}
+
+/// Recently received sequence numbers from a single peer.
+struct Window {
+    epoch: u16,
+    /// The highest received sequence number.
+    last: u16,
+    /// Bit N is set if `last - N` was received.
+    seen: u64,
+}
+
+impl Window {
+    const fn new(stamp: Stamp) -> Self {
+        Self {
+            epoch: stamp.epoch,
+            last: stamp.seq,
+            seen: 1,
+        }
+    }
+
+    /// Record the sequence number. Returns false if it's a duplicate.
+    fn accept(&mut self, stamp: Stamp, stats: &mut LinkStats) -> bool {
+        if stamp.epoch != self.epoch {
+            // The peer has restarted the network.
+            *self = Self::new(stamp);
+            return true;
+        }
+        let seq = stamp.seq;
+        let ahead = seq.wrapping_sub(self.last);
+        if ahead == 0 {
+            return false;
+        }
+        if ahead < u16::MAX / 2 {
+            stats.lost = stats.lost.saturating_add(u32::from(ahead - 1));
+            self.seen = if ahead >= WINDOW {
+                0
+            } else {
+                self.seen << ahead
+            };
+            self.seen |= 1;
+            self.last = seq;
+            return true;
+        }
+        let behind = self.last.wrapping_sub(seq);
+        if behind >= WINDOW {
+            // Too old to be a retry. Most probably, the peer has restarted.
+            self.last = seq;
+            self.seen = 1;
+            return true;
+        }
+        let bit = 1 << behind;
+        if self.seen & bit != 0 {
+            return false;
+        }
+        // A frame that arrived late is not lost anymore.
+        self.seen |= bit;
+        stats.lost = stats.lost.saturating_sub(1);
+        true
+    }
+}
+
+struct Inbound {
+    addr: Addr,
+    window: Window,
+    stats: LinkStats,
+}
+
+#[derive(Default)]
+pub struct SeqTracker {
+    /// The epoch of outgoing frames.
+    epoch: u16,
+    /// The next sequence number for each peer.
+    outbound: Vec<(Addr, u16)>,
+    inbound: Vec<Inbound>,
+}
+
+impl SeqTracker {
+    /// Get the stamp for the next frame to the peer.
+    ///
+    /// The sequence number is not used up until [`SeqTracker::advance`] is called,
+    /// so a frame that couldn't be sent isn't counted as lost by the peer.
+    #[must_use]
+    pub fn peek(&self, addr: Addr) -> Stamp {
+        let maybe_next = self.outbound.iter().find(|(a, _)| *a == addr);
+        let seq = maybe_next.map_or(0, |(_, next)| *next);
+        Stamp {
+            epoch: self.epoch,
+            seq,
+        }
+    }
+
+    /// Use up the sequence number of the frame sent to the peer.
+    pub fn advance(&mut self, addr: Addr) {
+        if let Some((_, next)) = self.outbound.iter_mut().find(|(a, _)| *a == addr) {
+            *next = next.wrapping_add(1);
+            return;
+        }
+        if self.outbound.len() >= MAX_TRACKED {
+            self.outbound.remove(0);
+        }
+        self.outbound.push((addr, 1));
+    }
+
+    /// Record the received frame. Returns false if it's a duplicate and must be dropped.
+    pub fn accept(&mut self, addr: Addr, stamp: Stamp) -> bool {
+        let Some(inbound) = self.inbound.iter_mut().find(|i| i.addr == addr) else {
+            if self.inbound.len() >= MAX_TRACKED {
+                self.inbound.remove(0);
+            }
+            self.inbound.push(Inbound {
+                addr,
+                window: Window::new(stamp),
+                stats: LinkStats {
+                    received: 1,
+                    ..LinkStats::default()
+                },
+            });
+            return true;
+        };
+        let stats = &mut inbound.stats;
+        if inbound.window.accept(stamp, stats) {
+            stats.received = stats.received.saturating_add(1);
+            true
+        } else {
+            stats.duplicates = stats.duplicates.saturating_add(1);
+            false
+        }
+    }
+
+    /// Get the stats for frames received from the peer.
+    #[must_use]
+    pub fn stats(&self, addr: Addr) -> LinkStats {
+        let maybe_inbound = self.inbound.iter().find(|i| i.addr == addr);
+        maybe_inbound.map(|i| i.stats).unwrap_or_default()
+    }
+
+    pub fn remove(&mut self, addr: Addr) {
+        self.outbound.retain(|(a, _)| *a != addr);
+        self.inbound.retain(|i| i.addr != addr);
+    }
+
+    /// Forget all peers and start a new epoch. The epoch must be random.
+    pub fn clear(&mut self, epoch: u16) {
+        self.epoch = epoch;
+        self.outbound.clear();
+        self.inbound.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: Addr = [1; 6];
+
+    const fn at(epoch: u16, seq: u16) -> Stamp {
+        Stamp { epoch, seq }
+    }
+
+    #[test]
+    fn stamp_roundtrip() {
+        let raw = stamp(at(0x1234, 0xABCD), b"hi");
+        assert_eq!(raw.len(), SEQ_SIZE + 2);
+        assert_eq!(unstamp(&raw), Some((at(0x1234, 0xABCD), &b"hi"[..])));
+        assert_eq!(unstamp(&raw[..3]), None);
+    }
+
+    #[test]
+    fn next() {
+        let mut t = SeqTracker::default();
+        t.clear(7);
+        assert_eq!(t.peek(A), at(7, 0));
+        // The frame wasn't sent, so the number is reused.
+        assert_eq!(t.peek(A), at(7, 0));
+        t.advance(A);
+        assert_eq!(t.peek(A), at(7, 1));
+        t.advance(A);
+        assert_eq!(t.peek(A), at(7, 2));
+        assert_eq!(t.peek([2; 6]), at(7, 0));
+        t.clear(8);
+        assert_eq!(t.peek(A), at(8, 0));
+    }
+
+    #[test]
+    fn duplicates_and_loss() {
+        let mut t = SeqTracker::default();
+        assert!(t.accept(A, at(1, 0)));
+        assert!(t.accept(A, at(1, 1)));
+        assert!(!t.accept(A, at(1, 1)));
+        assert!(t.accept(A, at(1, 4)));
+        assert_eq!(t.stats(A).lost, 2);
+        // A late frame is not lost anymore, but only once.
+        assert!(t.accept(A, at(1, 2)));
+        assert!(!t.accept(A, at(1, 2)));
+        let stats = t.stats(A);
+        assert_eq!(stats.received, 4);
+        assert_eq!(stats.duplicates, 2);
+        assert_eq!(stats.lost, 1);
+    }
+
+    #[test]
+    fn wrap_around() {
+        let mut t = SeqTracker::default();
+        assert!(t.accept(A, at(1, u16::MAX)));
+        assert!(t.accept(A, at(1, 0)));
+        assert!(!t.accept(A, at(1, u16::MAX)));
+        assert_eq!(t.stats(A).lost, 0);
+    }
+
+    #[test]
+    fn restart_starts_new_window() {
+        let mut t = SeqTracker::default();
+        for seq in 0..10 {
+            assert!(t.accept(A, at(1, seq)));
+        }
+        // The peer restarts the network and counts from zero again.
+        for seq in 0..10 {
+            assert!(t.accept(A, at(2, seq)));
+        }
+        assert!(!t.accept(A, at(2, 5)));
+        assert_eq!(t.stats(A).received, 20);
+    }
+}
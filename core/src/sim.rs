@@ -0,0 +1,136 @@
+//! A simulated lossy radio link.
+//!
+//! Used to check the network protocols on the host: frames can be dropped,
+//! duplicated, and delayed by a random time, which also makes them arrive out of order.
+use alloc::vec::Vec;
+
+pub struct LossyLink {
+    /// The state of the pseudo-random generator.
+    state: u32,
+    /// The chance (in percents) for a frame to be lost.
+    pub loss: u8,
+    /// The chance (in percents) for a delivered frame to be delivered twice.
+    pub duplicate: u8,
+    /// Max delay (in milliseconds) before a frame is delivered.
+    pub max_delay: u64,
+    /// Frames in flight with the time when they should be delivered.
+    in_flight: Vec<(u64, Vec<u8>)>,
+}
+
+impl LossyLink {
+    /// Create the link. The same seed always produces the same losses and delays.
+    #[must_use]
+    pub fn new(seed: u32, loss: u8, max_delay: u64) -> Self {
+        Self {
+            state: seed.max(1),
+            loss,
+            duplicate: 0,
+            max_delay,
+            in_flight: Vec::new(),
+        }
+    }
+
+    /// Send the frame. It might be lost or duplicated.
+    pub fn send(&mut self, frame: &[u8], now: u64) {
+        if self.random() % 100 < u32::from(self.loss) {
+            return;
+        }
+        if self.random() % 100 < u32::from(self.duplicate) {
+            let delay = self.delay();
+            self.in_flight.push((now + delay, frame.to_vec()));
+        }
+        let delay = self.delay();
+        self.in_flight.push((now + delay, frame.to_vec()));
+    }
+
+    /// Get a frame that has arrived by the given time.
+    pub fn recv(&mut self, now: u64) -> Option<Vec<u8>> {
+        let pos = self.in_flight.iter().position(|(at, _)| *at <= now)?;
+        Some(self.in_flight.swap_remove(pos).1)
+    }
+
+    /// The number of frames that are sent but not received yet.
+    #[must_use]
+    pub const fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    fn delay(&mut self) -> u64 {
+        u64::from(self.random()) % (self.max_delay + 1)
+    }
+
+    /// Xorshift32.
+    const fn random(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send frames numbered from 0 to `count` one per millisecond
+    /// and receive them all.
+    fn run(link: &mut LossyLink, count: u8) -> Vec<u8> {
+        let mut got = Vec::new();
+        for now in 0..1000 {
+            if let Ok(i) = u8::try_from(now) {
+                if i < count {
+                    link.send(&[i], now);
+                }
+            }
+            while let Some(frame) = link.recv(now) {
+                got.push(frame[0]);
+            }
+        }
+        assert_eq!(link.in_flight(), 0);
+        got
+    }
+
+    #[test]
+    fn perfect() {
+        let mut link = LossyLink::new(1, 0, 0);
+        assert_eq!(run(&mut link, 100), (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn loss() {
+        let mut link = LossyLink::new(1, 30, 0);
+        let got = run(&mut link, 200);
+        assert!(got.len() > 100 && got.len() < 180, "{}", got.len());
+        assert!(got.is_sorted());
+        let mut link = LossyLink::new(1, 100, 0);
+        assert!(run(&mut link, 10).is_empty());
+    }
+
+    #[test]
+    fn reorder() {
+        let mut link = LossyLink::new(1, 0, 20);
+        let mut got = run(&mut link, 200);
+        assert!(!got.is_sorted());
+        got.sort_unstable();
+        assert_eq!(got, (0..200).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn duplicate() {
+        let mut link = LossyLink::new(1, 0, 0);
+        link.duplicate = 20;
+        let mut got = run(&mut link, 200);
+        assert!(got.len() > 220 && got.len() < 260, "{}", got.len());
+        got.dedup();
+        assert_eq!(got, (0..200).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn deterministic() {
+        let mut a = LossyLink::new(5, 30, 20);
+        let mut b = LossyLink::new(5, 30, 20);
+        assert_eq!(run(&mut a, 100), run(&mut b, 100));
+    }
+}
@@ -0,0 +1,235 @@
+//! Virtual D-pad and analog stick derived from the touchpad position.
+//!
+//! The stick is relative to either the center of the pad or,
+//! for the "floating" stick, the point where the touch started.
+//! Y grows downwards, the same as for the touchpad coordinates.
+
+/// The stick value at full deflection.
+pub const MAX_DEFLECTION: i16 = 1000;
+
+pub const DPAD_UP: u8 = 1 << 0;
+pub const DPAD_RIGHT: u8 = 1 << 1;
+pub const DPAD_DOWN: u8 = 1 << 2;
+pub const DPAD_LEFT: u8 = 1 << 3;
+
+/// tan(22.5°) * 1000, the boundary between straight and diagonal sectors.
+const TAN_22_5: i32 = 414;
+
+#[derive(Clone, Copy)]
+pub struct StickConfig {
+    /// If true, the D-pad has 8 directions (including diagonals). Otherwise, 4.
+    pub eight_way: bool,
+    /// If true, the stick center is where the touch started. Otherwise, the pad center.
+    pub floating: bool,
+    /// The distance from the center (in pad units) within which there is no input.
+    pub dead_zone: u16,
+    /// The distance from the center (in pad units) that gives the full deflection.
+    pub radius: u16,
+}
+
+impl Default for StickConfig {
+    fn default() -> Self {
+        Self {
+            eight_way: true,
+            floating: true,
+            dead_zone: 100,
+            radius: 400,
+        }
+    }
+}
+
+impl StickConfig {
+    #[must_use]
+    pub const fn is_valid(&self) -> bool {
+        self.radius != 0 && self.dead_zone < self.radius
+    }
+}
+
+/// The state of the virtual D-pad and stick.
+#[derive(Clone, Copy, Default)]
+pub struct StickState {
+    /// Bitmask of `DPAD_*` directions.
+    pub dpad: u8,
+    /// From `-MAX_DEFLECTION` (left) to `MAX_DEFLECTION` (right).
+    pub x: i16,
+    /// From `-MAX_DEFLECTION` (up) to `MAX_DEFLECTION` (down).
+    pub y: i16,
+}
+
+#[derive(Default)]
+pub struct VirtualStick {
+    pub config: StickConfig,
+    /// The point where the current touch started.
+    origin: Option<(u16, u16)>,
+}
+
+impl VirtualStick {
+    /// Update the stick with the latest touch position.
+    ///
+    /// The `center` is the center of the pad, used for non-floating stick.
+    pub fn update(&mut self, pos: Option<(u16, u16)>, center: (u16, u16)) -> StickState {
+        let Some((x, y)) = pos else {
+            self.origin = None;
+            return StickState::default();
+        };
+        let origin = if self.config.floating {
+            *self.origin.get_or_insert((x, y))
+        } else {
+            center
+        };
+        let dx = i32::from(x) - i32::from(origin.0);
+        let dy = i32::from(y) - i32::from(origin.1);
+        #[expect(clippy::cast_sign_loss)]
+        let dist = ((dx * dx + dy * dy) as u32).isqrt();
+        if dist <= u32::from(self.config.dead_zone) {
+            return StickState::default();
+        }
+        let dpad = if self.config.eight_way {
+            dpad8(dx, dy)
+        } else {
+            dpad4(dx, dy)
+        };
+
+        // Scale the vector so that the radius is the full deflection
+        // and clamp it to the circle.
+        let radius = u32::from(self.config.radius).max(dist);
+        let max = i32::from(MAX_DEFLECTION);
+        #[expect(clippy::cast_possible_wrap)]
+        let radius = radius as i32;
+        #[expect(clippy::cast_possible_truncation)]
+        StickState {
+            dpad,
+            x: (dx * max / radius) as i16,
+            y: (dy * max / radius) as i16,
+        }
+    }
+}
+
+const fn dpad4(dx: i32, dy: i32) -> u8 {
+    if dx.abs() >= dy.abs() {
+        horizontal(dx)
+    } else {
+        vertical(dy)
+    }
+}
+
+const fn dpad8(dx: i32, dy: i32) -> u8 {
+    let (ax, ay) = (dx.abs(), dy.abs());
+    if ay * 1000 < ax * TAN_22_5 {
+        horizontal(dx)
+    } else if ax * 1000 < ay * TAN_22_5 {
+        vertical(dy)
+    } else {
+        horizontal(dx) | vertical(dy)
+    }
+}
+
+const fn horizontal(dx: i32) -> u8 {
+    if dx < 0 {
+        DPAD_LEFT
+    } else {
+        DPAD_RIGHT
+    }
+}
+
+const fn vertical(dy: i32) -> u8 {
+    if dy < 0 {
+        DPAD_UP
+    } else {
+        DPAD_DOWN
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CENTER: (u16, u16) = (500, 500);
+
+    fn stick(eight_way: bool, floating: bool) -> VirtualStick {
+        VirtualStick {
+            config: StickConfig {
+                eight_way,
+                floating,
+                dead_zone: 100,
+                radius: 400,
+            },
+            origin: None,
+        }
+    }
+
+    /// The D-pad state for the touch at the given offset from the pad center.
+    fn dpad(s: &mut VirtualStick, dx: i32, dy: i32) -> u8 {
+        let x = u16::try_from(i32::from(CENTER.0) + dx).unwrap();
+        let y = u16::try_from(i32::from(CENTER.1) + dy).unwrap();
+        s.update(Some((x, y)), CENTER).dpad
+    }
+
+    #[test]
+    fn four_way_sectors() {
+        let mut s = stick(false, false);
+        assert_eq!(dpad(&mut s, 200, 0), DPAD_RIGHT);
+        assert_eq!(dpad(&mut s, 0, -200), DPAD_UP);
+        // On the diagonal, horizontal wins.
+        assert_eq!(dpad(&mut s, 200, 200), DPAD_RIGHT);
+        assert_eq!(dpad(&mut s, -200, -200), DPAD_LEFT);
+        assert_eq!(dpad(&mut s, 200, 201), DPAD_DOWN);
+        assert_eq!(dpad(&mut s, -200, -201), DPAD_UP);
+    }
+
+    #[test]
+    fn eight_way_sectors() {
+        let mut s = stick(true, false);
+        // The boundary between the sectors is at 22.5°.
+        assert_eq!(dpad(&mut s, 400, 165), DPAD_RIGHT);
+        assert_eq!(dpad(&mut s, 400, 166), DPAD_RIGHT | DPAD_DOWN);
+        assert_eq!(dpad(&mut s, 165, -400), DPAD_UP);
+        assert_eq!(dpad(&mut s, 166, -400), DPAD_UP | DPAD_RIGHT);
+        assert_eq!(dpad(&mut s, -400, -166), DPAD_LEFT | DPAD_UP);
+        assert_eq!(dpad(&mut s, -400, -165), DPAD_LEFT);
+        assert_eq!(dpad(&mut s, -200, 200), DPAD_LEFT | DPAD_DOWN);
+        assert_eq!(dpad(&mut s, -165, 400), DPAD_DOWN);
+    }
+
+    #[test]
+    fn dead_zone_edge() {
+        let mut s = stick(true, false);
+        let state = s.update(Some((600, 500)), CENTER);
+        assert_eq!((state.dpad, state.x, state.y), (0, 0, 0));
+        let state = s.update(Some((601, 500)), CENTER);
+        assert_eq!((state.dpad, state.x, state.y), (DPAD_RIGHT, 252, 0));
+        let state = s.update(Some((500, 399)), CENTER);
+        assert_eq!((state.dpad, state.x, state.y), (DPAD_UP, 0, -252));
+    }
+
+    #[test]
+    fn clamp_to_radius() {
+        let mut s = stick(true, false);
+        let state = s.update(Some((900, 500)), CENTER);
+        assert_eq!((state.x, state.y), (MAX_DEFLECTION, 0));
+        // Past the radius, the direction is kept.
+        let state = s.update(Some((800, 900)), CENTER);
+        assert_eq!((state.x, state.y), (600, 800));
+    }
+
+    #[test]
+    fn floating_origin() {
+        let mut s = stick(true, true);
+        // The touch start is the center, wherever it is on the pad.
+        let state = s.update(Some((100, 100)), CENTER);
+        assert_eq!(state.dpad, 0);
+        let state = s.update(Some((300, 100)), CENTER);
+        assert_eq!((state.dpad, state.x, state.y), (DPAD_RIGHT, 500, 0));
+        // Releasing the touch resets the origin.
+        assert_eq!(s.update(None, CENTER).dpad, 0);
+        let state = s.update(Some((300, 100)), CENTER);
+        assert_eq!(state.dpad, 0);
+        let state = s.update(Some((300, 300)), CENTER);
+        assert_eq!((state.dpad, state.x, state.y), (DPAD_DOWN, 0, 500));
+
+        // The fixed stick is relative to the pad center.
+        let mut s = stick(true, false);
+        let state = s.update(Some((100, 500)), CENTER);
+        assert_eq!((state.dpad, state.x), (DPAD_LEFT, -MAX_DEFLECTION));
+    }
+}
@@ -0,0 +1,523 @@
+//! Ordered reliable streams over ESP-NOW.
+//!
+//! ESP-NOW retries a frame until the radio ack, but the ack doesn't mean
+//! the frame was processed, and nothing keeps frames in order. A stream adds
+//! application-level acks on top: the sender keeps a window of unacknowledged
+//! segments and retransmits them on timeout, and the receiver delivers
+//! segments strictly in order and acks them cumulatively.
+//!
+//! Both sides must open the stream. Stream frames from peers without
+//! an open stream are ignored.
+use crate::frag::MAX_FRAME;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+/// Max number of segments sent but not acknowledged yet.
+pub const WINDOW: u16 = 8;
+/// Max number of segments waiting for a free slot in the window.
+pub const MAX_PENDING: usize = 16;
+/// Max number of received segments waiting to be read.
+///
+/// When the limit is reached, the next segments are not acknowledged,
+/// so the sender keeps them until they can be received.
+pub const MAX_READY: usize = 16;
+/// Max size of the data in a segment.
+pub const MAX_SEGMENT: usize = MAX_FRAME - 3;
+/// Max number of open streams.
+pub const MAX_STREAMS: usize = 8;
+/// After so many retransmissions of a segment the stream is considered broken.
+pub const MAX_RETRANSMITS: u8 = 10;
+/// The retransmission timeout in milliseconds. Doubles on every retransmission.
+const MIN_RTO: u64 = 100;
+const MAX_RTO: u64 = 2000;
+
+// Frame kinds. The values must not overlap with kinds in the frag module.
+const KIND_DATA: u8 = 2;
+const KIND_ACK: u8 = 3;
+
+type Addr = [u8; 6];
+
+pub enum Packet<'a> {
+    Data {
+        seq: u16,
+        data: &'a [u8],
+    },
+    /// Everything before `next` is received.
+    Ack {
+        next: u16,
+    },
+}
+
+impl<'a> Packet<'a> {
+    /// Parse a stream frame. Returns None if it's not a stream frame.
+    #[must_use]
+    pub fn parse(frame: &'a [u8]) -> Option<Self> {
+        let (&kind, rest) = frame.split_first()?;
+        let (seq, rest) = rest.split_first_chunk()?;
+        let seq = u16::from_le_bytes(*seq);
+        match kind {
+            KIND_DATA => Some(Self::Data { seq, data: rest }),
+            KIND_ACK if rest.is_empty() => Some(Self::Ack { next: seq }),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, seq, data) = match self {
+            Self::Data { seq, data } => (KIND_DATA, *seq, *data),
+            Self::Ack { next } => (KIND_ACK, *next, &[][..]),
+        };
+        let mut frame = Vec::with_capacity(3 + data.len());
+        frame.push(kind);
+        frame.extend_from_slice(&seq.to_le_bytes());
+        frame.extend_from_slice(data);
+        frame
+    }
+}
+
+struct Segment {
+    seq: u16,
+    data: Box<[u8]>,
+    /// When the segment was last sent. None if it wasn't sent yet.
+    sent_at: Option<u64>,
+    retransmits: u8,
+}
+
+#[derive(Default)]
+pub struct Stream {
+    /// The sequence number for the next segment added to the window.
+    next_seq: u16,
+    /// Segments in the window, oldest first.
+    unacked: VecDeque<Segment>,
+    /// Segments waiting for a free slot in the window.
+    pending: VecDeque<Box<[u8]>>,
+    /// The sequence number of the next segment to deliver.
+    expected: u16,
+    /// Segments received ahead of a missing one.
+    early: Vec<(u16, Box<[u8]>)>,
+    /// Segments received in order and not read yet.
+    ready: VecDeque<Box<[u8]>>,
+    /// If an ack must be sent on the next poll.
+    ack_due: bool,
+    /// How many times segments were retransmitted.
+    pub retransmits: u32,
+    /// If a segment wasn't acknowledged after all retransmissions.
+    pub failed: bool,
+}
+
+impl Stream {
+    /// Queue the data for sending. Returns false if the data is too big
+    /// or there are too many queued segments.
+    pub fn write(&mut self, data: &[u8]) -> bool {
+        if self.failed || data.len() > MAX_SEGMENT || self.pending.len() >= MAX_PENDING {
+            return false;
+        }
+        self.pending.push_back(data.into());
+        true
+    }
+
+    /// The number of segments not acknowledged yet, including pending ones.
+    #[must_use]
+    pub fn unacked(&self) -> usize {
+        self.unacked.len() + self.pending.len()
+    }
+
+    #[must_use]
+    pub fn status(&self) -> Status {
+        Status {
+            unacked: u8::try_from(self.unacked()).unwrap_or(u8::MAX),
+            retransmits: self.retransmits,
+            failed: self.failed,
+        }
+    }
+
+    /// Get the next segment received in order.
+    pub fn read(&mut self) -> Option<Box<[u8]>> {
+        let data = self.ready.pop_front()?;
+        if self.deliver_early() {
+            self.ack_due = true;
+        }
+        Some(data)
+    }
+
+    /// Send frames that must be sent now: new segments, retransmissions, and acks.
+    ///
+    /// The callback returns false if the frame couldn't be queued for sending.
+    /// Such frames are tried again on the next poll.
+    pub fn poll(&mut self, now: u64, mut send: impl FnMut(&[u8]) -> bool) {
+        while self.unacked.len() < usize::from(WINDOW) {
+            let Some(data) = self.pending.pop_front() else {
+                break;
+            };
+            self.unacked.push_back(Segment {
+                seq: self.next_seq,
+                data,
+                sent_at: None,
+                retransmits: 0,
+            });
+            self.next_seq = self.next_seq.wrapping_add(1);
+        }
+
+        for segment in &mut self.unacked {
+            let retransmit = segment.sent_at.is_some();
+            if let Some(sent_at) = segment.sent_at {
+                let rto = (MIN_RTO << segment.retransmits).min(MAX_RTO);
+                if now.saturating_sub(sent_at) < rto {
+                    continue;
+                }
+                if segment.retransmits >= MAX_RETRANSMITS {
+                    self.failed = true;
+                    break;
+                }
+            }
+            let packet = Packet::Data {
+                seq: segment.seq,
+                data: &segment.data,
+            };
+            if !send(&packet.encode()) {
+                break;
+            }
+            segment.sent_at = Some(now);
+            if retransmit {
+                segment.retransmits += 1;
+                self.retransmits = self.retransmits.saturating_add(1);
+            }
+        }
+        if self.failed {
+            self.unacked.clear();
+            self.pending.clear();
+        }
+        if self.ack_due {
+            let packet = Packet::Ack {
+                next: self.expected,
+            };
+            self.ack_due = !send(&packet.encode());
+        }
+    }
+
+    /// Handle a frame received from the peer.
+    pub fn receive(&mut self, packet: &Packet<'_>) {
+        match *packet {
+            Packet::Ack { next } => {
+                while let Some(segment) = self.unacked.front() {
+                    if !is_before(segment.seq, next) || segment.sent_at.is_none() {
+                        break;
+                    }
+                    self.unacked.pop_front();
+                }
+            }
+            Packet::Data { seq, data } => {
+                // Ack even duplicates: the previous ack might have been lost.
+                self.ack_due = true;
+                if seq == self.expected {
+                    // If nobody reads the segments, stop accepting them.
+                    // The sender will retransmit the segment later.
+                    if self.ready.len() >= MAX_READY {
+                        return;
+                    }
+                    self.ready.push_back(data.into());
+                    self.expected = self.expected.wrapping_add(1);
+                    self.deliver_early();
+                } else if is_before(self.expected, seq)
+                    && seq.wrapping_sub(self.expected) < WINDOW
+                    && !self.early.iter().any(|(s, _)| *s == seq)
+                {
+                    self.early.push((seq, data.into()));
+                }
+            }
+        }
+    }
+
+    /// Move the segments received ahead of time that are now in order into `ready`.
+    ///
+    /// Returns true if any segment was moved.
+    fn deliver_early(&mut self) -> bool {
+        let mut moved = false;
+        while self.ready.len() < MAX_READY {
+            let expected = self.expected;
+            let Some(pos) = self.early.iter().position(|(s, _)| *s == expected) else {
+                break;
+            };
+            let (_, data) = self.early.swap_remove(pos);
+            self.ready.push_back(data);
+            self.expected = expected.wrapping_add(1);
+            moved = true;
+        }
+        moved
+    }
+}
+
+/// Check if the sequence number `a` comes before `b`, accounting for wrapping.
+const fn is_before(a: u16, b: u16) -> bool {
+    a != b && b.wrapping_sub(a) < u16::MAX / 2
+}
+
+/// The state of a stream reported to the main chip.
+pub struct Status {
+    /// The number of segments not acknowledged yet, including pending ones.
+    pub unacked: u8,
+    pub retransmits: u32,
+    pub failed: bool,
+}
+
+/// Streams with all peers.
+#[derive(Default)]
+pub struct Streams {
+    streams: Vec<(Addr, Stream)>,
+}
+
+impl Streams {
+    /// Open a stream with the peer. Returns false if there are too many open streams.
+    pub fn open(&mut self, addr: Addr) -> bool {
+        if self.get(addr).is_some() {
+            return true;
+        }
+        if self.streams.len() >= MAX_STREAMS {
+            return false;
+        }
+        self.streams.push((addr, Stream::default()));
+        true
+    }
+
+    pub fn close(&mut self, addr: Addr) {
+        self.streams.retain(|(a, _)| *a != addr);
+    }
+
+    #[must_use]
+    pub fn get(&self, addr: Addr) -> Option<&Stream> {
+        self.streams
+            .iter()
+            .find(|(a, _)| *a == addr)
+            .map(|(_, s)| s)
+    }
+
+    pub fn get_mut(&mut self, addr: Addr) -> Option<&mut Stream> {
+        self.streams
+            .iter_mut()
+            .find(|(a, _)| *a == addr)
+            .map(|(_, s)| s)
+    }
+
+    /// Handle a stream frame from the peer.
+    ///
+    /// Ignored if the stream with the peer isn't open.
+    pub fn receive(&mut self, addr: Addr, packet: &Packet<'_>) {
+        if let Some(stream) = self.get_mut(addr) {
+            stream.receive(packet);
+        }
+    }
+
+    /// Get the next segment received in order from any peer.
+    pub fn read(&mut self) -> Option<(Addr, Box<[u8]>)> {
+        self.streams
+            .iter_mut()
+            .find_map(|(addr, stream)| stream.read().map(|data| (*addr, data)))
+    }
+
+    /// Send frames that must be sent now to all peers.
+    ///
+    /// See [`Stream::poll`].
+    pub fn poll(&mut self, now: u64, mut send: impl FnMut(Addr, &[u8]) -> bool) {
+        for (addr, stream) in &mut self.streams {
+            stream.poll(now, |frame| send(*addr, frame));
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.streams.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::LossyLink;
+
+    const A: Addr = [1; 6];
+
+    fn send_all(stream: &mut Stream, link: &mut LossyLink, now: u64) {
+        stream.poll(now, |frame| {
+            link.send(frame, now);
+            true
+        });
+    }
+
+    fn recv_all(stream: &mut Stream, link: &mut LossyLink, now: u64) {
+        while let Some(frame) = link.recv(now) {
+            stream.receive(&Packet::parse(&frame).unwrap());
+        }
+    }
+
+    /// Send `count` segments from one stream to another over the links
+    /// and return what's received.
+    fn transfer(ab: &mut LossyLink, ba: &mut LossyLink, count: u32) -> (Stream, Vec<u32>) {
+        let mut a = Stream::default();
+        let mut b = Stream::default();
+        let mut sent = 0;
+        let mut got = Vec::new();
+        for now in 0..200_000 {
+            if sent < count && a.write(&sent.to_le_bytes()) {
+                sent += 1;
+            }
+            send_all(&mut a, ab, now);
+            send_all(&mut b, ba, now);
+            recv_all(&mut b, ab, now);
+            recv_all(&mut a, ba, now);
+            while let Some(data) = b.read() {
+                got.push(u32::from_le_bytes((*data).try_into().unwrap()));
+            }
+            if got.len() == count as usize && a.unacked() == 0 {
+                break;
+            }
+        }
+        (a, got)
+    }
+
+    #[test]
+    fn in_order_over_lossy_link() {
+        let mut ab = LossyLink::new(7, 30, 50);
+        let mut ba = LossyLink::new(9, 30, 50);
+        ab.duplicate = 10;
+        ba.duplicate = 10;
+        let (a, got) = transfer(&mut ab, &mut ba, 500);
+        assert!(!a.failed);
+        assert!(a.retransmits > 0);
+        assert_eq!(got, (0..500).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn perfect_link_has_no_retransmits() {
+        let mut ab = LossyLink::new(1, 0, 5);
+        let mut ba = LossyLink::new(2, 0, 5);
+        let (a, got) = transfer(&mut ab, &mut ba, 100);
+        assert_eq!(a.retransmits, 0);
+        assert_eq!(got.len(), 100);
+    }
+
+    #[test]
+    fn dead_link_fails() {
+        let mut a = Stream::default();
+        assert!(a.write(b"hi"));
+        for now in (0..100_000).step_by(10) {
+            a.poll(now, |_| true);
+        }
+        assert!(a.failed);
+        assert_eq!(a.unacked(), 0);
+        assert!(!a.write(b"hi"));
+    }
+
+    #[test]
+    fn refused_frames_are_not_sent() {
+        let mut a = Stream::default();
+        assert!(a.write(b"hi"));
+        let mut frames = 0;
+        // The send queue is full for a long time.
+        for now in 0..10_000 {
+            a.poll(now, |_| {
+                frames += 1;
+                false
+            });
+        }
+        assert_eq!(frames, 10_000);
+        assert_eq!(a.retransmits, 0);
+        assert!(!a.failed);
+        // The segment is not acked before it's actually sent.
+        a.receive(&Packet::Ack { next: 1 });
+        assert_eq!(a.unacked(), 1);
+        a.poll(10_000, |_| true);
+        assert_eq!(a.retransmits, 0);
+        a.receive(&Packet::Ack { next: 1 });
+        assert_eq!(a.unacked(), 0);
+    }
+
+    #[test]
+    fn refused_ack_is_sent_later() {
+        let mut b = Stream::default();
+        b.receive(&Packet::Data {
+            seq: 0,
+            data: b"hi",
+        });
+        b.poll(0, |_| false);
+        let mut acks = Vec::new();
+        b.poll(1, |frame| {
+            acks.push(frame.to_vec());
+            true
+        });
+        assert_eq!(acks, [Packet::Ack { next: 1 }.encode()]);
+    }
+
+    fn acks(stream: &mut Stream, now: u64) -> Vec<Vec<u8>> {
+        let mut acks = Vec::new();
+        stream.poll(now, |frame| {
+            acks.push(frame.to_vec());
+            true
+        });
+        acks
+    }
+
+    #[test]
+    fn bounded_ready() {
+        let mut b = Stream::default();
+        let max = u16::try_from(MAX_READY).unwrap();
+        // Nobody reads the segments.
+        for seq in 0..max {
+            b.receive(&Packet::Data { seq, data: b"x" });
+        }
+        b.receive(&Packet::Data {
+            seq: max + 1,
+            data: b"x",
+        });
+        b.receive(&Packet::Data {
+            seq: max,
+            data: b"x",
+        });
+        assert_eq!(b.ready.len(), MAX_READY);
+        // The segment that didn't fit is not acked.
+        assert_eq!(acks(&mut b, 0), [Packet::Ack { next: max }.encode()]);
+
+        // Reading frees the space for the retransmitted segment.
+        assert!(b.read().is_some());
+        b.receive(&Packet::Data {
+            seq: max,
+            data: b"x",
+        });
+        assert_eq!(acks(&mut b, 1), [Packet::Ack { next: max + 1 }.encode()]);
+
+        // And for the segment received ahead of time.
+        assert!(b.read().is_some());
+        assert_eq!(b.ready.len(), MAX_READY);
+        assert_eq!(acks(&mut b, 2), [Packet::Ack { next: max + 2 }.encode()]);
+    }
+
+    #[test]
+    fn receive_requires_open() {
+        let mut streams = Streams::default();
+        let packet = Packet::Data {
+            seq: 0,
+            data: b"hi",
+        };
+        streams.receive(A, &packet);
+        assert!(streams.get(A).is_none());
+        assert!(streams.read().is_none());
+
+        assert!(streams.open(A));
+        streams.receive(A, &packet);
+        let (addr, data) = streams.read().unwrap();
+        assert_eq!(addr, A);
+        assert_eq!(&*data, b"hi");
+    }
+
+    #[test]
+    fn max_streams() {
+        let mut streams = Streams::default();
+        for i in 0..MAX_STREAMS {
+            assert!(streams.open([u8::try_from(i).unwrap(); 6]));
+        }
+        assert!(!streams.open([0xFF; 6]));
+        assert!(streams.open([0; 6]));
+        streams.close([0; 6]);
+        assert!(streams.open([0xFF; 6]));
+    }
+}
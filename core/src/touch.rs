@@ -0,0 +1,265 @@
+/// Max value of the Z level (pressure) reported by Pinnacle.
+pub const MAX_Z: u8 = 63;
+
+/// A single touchpad reading after the touch detection.
+#[derive(Clone, Copy, Default)]
+pub struct Touch {
+    pub x: u16,
+    pub y: u16,
+    /// Z level: how close the finger is to the pad or how hard it's pressed.
+    ///
+    /// Proportional to the contact area, so it also reflects the finger size.
+    pub z: u8,
+    pub state: TouchState,
+}
+
+impl Touch {
+    /// Coordinates of the touch if the pad is touched (and not just hovered).
+    #[must_use]
+    pub const fn pos(&self) -> Option<(u16, u16)> {
+        match self.state {
+            TouchState::Touched => Some((self.x, self.y)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum TouchState {
+    /// Nothing is near the pad.
+    #[default]
+    Released,
+    /// Something is near the pad but the Z level is below the touch threshold.
+    Hovering,
+    /// The pad is touched.
+    Touched,
+}
+
+impl From<TouchState> for u8 {
+    fn from(value: TouchState) -> Self {
+        match value {
+            TouchState::Released => 0,
+            TouchState::Hovering => 1,
+            TouchState::Touched => 2,
+        }
+    }
+}
+
+/// Z levels used to decide if the pad is touched.
+///
+/// The pad becomes touched when Z reaches `press` and stays touched
+/// until Z drops below `release`. The gap between the two is the hysteresis
+/// that prevents flickering when the pressure is near the threshold.
+#[derive(Clone, Copy)]
+pub struct Threshold {
+    pub press: u8,
+    pub release: u8,
+}
+
+impl Default for Threshold {
+    fn default() -> Self {
+        // Same as Pinnacle's own "touched": any non-zero Z.
+        Self {
+            press: 1,
+            release: 1,
+        }
+    }
+}
+
+impl Threshold {
+    #[must_use]
+    pub const fn is_valid(&self) -> bool {
+        self.release != 0 && self.release <= self.press && self.press <= MAX_Z
+    }
+}
+
+/// Tracks the touch state between readings to apply the hysteresis.
+#[derive(Default)]
+pub struct TouchDetector {
+    pub threshold: Threshold,
+    touched: bool,
+}
+
+impl TouchDetector {
+    /// Classify a raw reading from the pad.
+    pub const fn update(&mut self, x: u16, y: u16, z: u8) -> Touch {
+        self.touched = if self.touched {
+            z >= self.threshold.release
+        } else {
+            z >= self.threshold.press
+        };
+        let state = if self.touched {
+            TouchState::Touched
+        } else if z != 0 {
+            TouchState::Hovering
+        } else {
+            TouchState::Released
+        };
+        Touch { x, y, z, state }
+    }
+}
+
+/// Max X coordinate that Pinnacle reports in absolute mode.
+pub const MAX_X: u16 = 2047;
+/// Max Y coordinate that Pinnacle reports in absolute mode.
+pub const MAX_Y: u16 = 1535;
+
+/// ADC attenuation of the touchpad.
+///
+/// `X1` gives the highest sensitivity (thick cases, gloves),
+/// `X4` gives the lowest.
+#[derive(Clone, Copy, Default)]
+pub enum AdcGain {
+    #[default]
+    X1,
+    X2,
+    X3,
+    X4,
+}
+
+impl From<AdcGain> for u8 {
+    fn from(value: AdcGain) -> Self {
+        match value {
+            AdcGain::X1 => 0,
+            AdcGain::X2 => 1,
+            AdcGain::X3 => 2,
+            AdcGain::X4 => 3,
+        }
+    }
+}
+
+impl TryFrom<u8> for AdcGain {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, ()> {
+        match value {
+            0 => Ok(Self::X1),
+            1 => Ok(Self::X2),
+            2 => Ok(Self::X3),
+            3 => Ok(Self::X4),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Clockwise rotation of the touchpad relative to the device.
+#[derive(Clone, Copy, Default)]
+pub enum Rotation {
+    #[default]
+    R0,
+    R90,
+    R180,
+    R270,
+}
+
+impl From<Rotation> for u8 {
+    fn from(value: Rotation) -> Self {
+        match value {
+            Rotation::R0 => 0,
+            Rotation::R90 => 1,
+            Rotation::R180 => 2,
+            Rotation::R270 => 3,
+        }
+    }
+}
+
+impl TryFrom<u8> for Rotation {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, ()> {
+        match value {
+            0 => Ok(Self::R0),
+            1 => Ok(Self::R90),
+            2 => Ok(Self::R180),
+            3 => Ok(Self::R270),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Touchpad configuration that can be changed at runtime.
+#[derive(Clone, Copy)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct PadConfig {
+    pub gain: AdcGain,
+    /// Enable the noise filter built into the touchpad.
+    pub filter: bool,
+    pub invert_x: bool,
+    pub invert_y: bool,
+    pub swap_xy: bool,
+    /// Applied after inversion and swapping.
+    pub rotation: Rotation,
+}
+
+impl Default for PadConfig {
+    fn default() -> Self {
+        Self {
+            gain: AdcGain::default(),
+            filter: true,
+            invert_x: false,
+            invert_y: false,
+            swap_xy: false,
+            rotation: Rotation::default(),
+        }
+    }
+}
+
+impl PadConfig {
+    pub const SIZE: usize = 3;
+
+    /// Transform raw coordinates according to the pad orientation.
+    #[must_use]
+    pub const fn orient(&self, x: u16, y: u16) -> (u16, u16) {
+        let (mut x, mut y) = (x, y);
+        let (mut w, mut h) = (MAX_X, MAX_Y);
+        if self.invert_x {
+            x = w.saturating_sub(x);
+        }
+        if self.invert_y {
+            y = h.saturating_sub(y);
+        }
+        if self.swap_xy {
+            (x, y) = (y, x);
+            (w, h) = (h, w);
+        }
+        match self.rotation {
+            Rotation::R0 => (x, y),
+            Rotation::R90 => (h.saturating_sub(y), x),
+            Rotation::R180 => (w.saturating_sub(x), h.saturating_sub(y)),
+            Rotation::R270 => (y, w.saturating_sub(x)),
+        }
+    }
+
+    /// The center of the pad after applying the orientation.
+    #[must_use]
+    pub const fn center(&self) -> (u16, u16) {
+        let rotated = matches!(self.rotation, Rotation::R90 | Rotation::R270);
+        if self.swap_xy == rotated {
+            (MAX_X / 2, MAX_Y / 2)
+        } else {
+            (MAX_Y / 2, MAX_X / 2)
+        }
+    }
+
+    #[must_use]
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let flags = u8::from(self.filter)
+            | u8::from(self.invert_x) << 1
+            | u8::from(self.invert_y) << 2
+            | u8::from(self.swap_xy) << 3;
+        [self.gain.into(), flags, self.rotation.into()]
+    }
+
+    #[must_use]
+    pub fn from_bytes(raw: [u8; Self::SIZE]) -> Option<Self> {
+        let [gain, flags, rotation] = raw;
+        Some(Self {
+            gain: gain.try_into().ok()?,
+            filter: flags & 1 != 0,
+            invert_x: flags & (1 << 1) != 0,
+            invert_y: flags & (1 << 2) != 0,
+            swap_xy: flags & (1 << 3) != 0,
+            rotation: rotation.try_into().ok()?,
+        })
+    }
+}
@@ -1,7 +1,32 @@
-use crate::{retries, wifi::WifiManager, ErrPrinter};
-use alloc::{boxed::Box, string::String};
+use crate::{
+    beacon::{Beacon, Discovered, PROTOCOL_VERSION},
+    channel::{self, Sweep, DEFAULT_CHANNEL},
+    combo::{Action, ComboDetector},
+    filter::Smoother,
+    frag::{self, Frame, Reassembler, MAX_MESSAGE},
+    health::PadHealth,
+    idle::IdleDetector,
+    input::{Axis, Capabilities, InputState},
+    latency::Latency,
+    layout::ButtonMap,
+    pairing::{self, Identity, Message, Pairing, KEY_SIZE, NONCE_SIZE},
+    peers::{Access, Peers},
+    pinnacle::{self, Pinnacle},
+    power::Power,
+    proto,
+    replay::Replay,
+    retries::{self, Class},
+    sampler::{Aggregator, Sampler, BUTTONS},
+    seq::{self, SeqTracker},
+    settings::Settings,
+    stick::{StickState, VirtualStick},
+    stream::{Packet, Stream, Streams},
+    touch::{Touch, TouchDetector, TouchState},
+    wifi::WifiManager,
+    ErrPrinter,
+};
+use alloc::{boxed::Box, collections::VecDeque, string::String, vec::Vec};
 use anyhow::{bail, Result};
-use cirque_pinnacle::{Absolute, Touchpad};
 use core::convert::Infallible;
 use embedded_hal_bus::spi::ExclusiveDevice;
 use embedded_storage::Storage;
@@ -12,16 +37,36 @@ use esp_bootloader_esp_idf::{
 use esp_hal::{
     delay::Delay,
     gpio::{Input, Output},
+    rng::Rng,
     spi::master::Spi,
+    system::software_reset,
     Blocking,
 };
 use esp_println::println;
 use esp_radio::esp_now::*;
 use esp_storage::FlashStorage;
-use firefly_types::spi::{Request, Response};
+use firefly_types::spi::{Request, Response, SendStatus};
+use firefly_types::wifi::Status;
 
 type PadSpi<'a> = ExclusiveDevice<Spi<'a, Blocking>, Output<'a>, Delay>;
+type PadError =
+    pinnacle::Error<embedded_hal_bus::spi::DeviceError<esp_hal::spi::Error, Infallible>>;
 type RawInput = (Option<(u16, u16)>, u8);
+
+/// How often (in Hz) to read the touchpad for idle detection
+/// when background sampling is disabled.
+const IDLE_POLL_RATE: u16 = 10;
+/// What the main chip receives when a compatible device is discovered.
+const HELLO: &[u8] = b"HELLO";
+/// Max size of a message that fits into the `NetRecv` response.
+///
+/// Bigger messages must be read using the `RecvLarge` extension request.
+const MAX_INCOMING: usize = 240;
+/// Max number of received big messages waiting to be read.
+const MAX_LARGE: usize = 2;
+/// Max size of a chunk of a big message in the `RecvLarge` response.
+const LARGE_CHUNK: usize = 200;
+
 pub type Addr = [u8; 6];
 
 pub struct Buttons<'a> {
@@ -30,6 +75,10 @@ pub struct Buttons<'a> {
     pub w: Input<'a>,
     pub n: Input<'a>,
     pub menu: Input<'a>,
+    /// Buttons provided by the board variant or an accessory.
+    pub extra: Vec<Input<'a>>,
+    /// Analog axes, like an analog stick.
+    pub axes: Vec<Axis<'a>>,
 }
 
 /// An extension for [`Response`] that owns fields that the original struct borrows.
@@ -38,41 +87,125 @@ pub enum RespBuf<'a> {
     Incoming([u8; 6], Box<[u8]>),
     Scan([String; 6]),
     TcpChunk(Box<[u8]>),
+    Ext(proto::Response),
     Err(String),
 }
 
 pub struct Actor<'a> {
-    pad: Touchpad<PadSpi<'a>, Absolute>,
+    pad: Pinnacle<PadSpi<'a>>,
+    health: PadHealth,
+    touch: TouchDetector,
+    smoother: Smoother,
+    stick: VirtualStick,
+    combos: ComboDetector,
+    sampler: Sampler,
+    frame: Aggregator,
+    replay: Replay,
+    latency: Latency,
+    idle: IdleDetector,
+    idle_poll: Sampler,
+    /// Bitmask of `proto::FLAG_*` system flags.
+    flags: u8,
     manager: EspNowManager<'a>,
     receiver: EspNowReceiver<'a>,
     buttons: Buttons<'a>,
     wifi: WifiManager<'a>,
     flash: FlashStorage<'a>,
+    settings: Settings,
+    power: Power<'a>,
+    pairing: Pairing,
+    /// The beacon sent when advertising.
+    beacon: Beacon,
+    /// If beacons from devices running other apps are ignored.
+    same_app: bool,
+    /// If the channel was set by the main chip instead of following host beacons.
+    channel_set: bool,
+    /// Looking for beacons on all channels after the network is started.
+    sweep: Option<Sweep>,
+    discovered: Discovered,
+    peers: Peers,
+    reassembler: Reassembler,
+    seq: SeqTracker,
+    streams: Streams,
+    /// The ID of the next outgoing message, used to group its fragments.
+    next_msg_id: u8,
+    /// The big message being uploaded by the main chip for sending.
+    staged: Vec<u8>,
+    /// Received messages that are too big for `NetRecv`.
+    large: VecDeque<(Addr, Box<[u8]>)>,
+    /// The big message being read by the main chip.
+    reading: Option<(Addr, Box<[u8]>)>,
 }
 
 impl<'a> Actor<'a> {
     #[must_use]
     pub fn new(
         esp_now: EspNow<'a>,
-        pad: Touchpad<PadSpi<'a>, Absolute>,
+        pad: Pinnacle<PadSpi<'a>>,
         buttons: Buttons<'a>,
         wifi: WifiManager<'a>,
         flash: FlashStorage<'a>,
+        settings: Settings,
+        power: Power<'a>,
     ) -> Self {
         let (manager, _sender, receiver) = esp_now.split();
+        let mut seed = [0u8; KEY_SIZE];
+        Rng::new().read(&mut seed);
         let mut actor = Self {
             pad,
+            health: PadHealth::new(),
+            touch: TouchDetector::default(),
+            smoother: Smoother::default(),
+            stick: VirtualStick::default(),
+            combos: ComboDetector::new(),
+            sampler: Sampler::default(),
+            frame: Aggregator::default(),
+            replay: Replay::new(),
+            latency: Latency::default(),
+            idle: IdleDetector::new(),
+            idle_poll: Sampler::default(),
+            flags: 0,
             manager,
             receiver,
             buttons,
             wifi,
             flash,
+            settings,
+            power,
+            pairing: Pairing::new(Identity::new(seed)),
+            beacon: Beacon {
+                protocol: PROTOCOL_VERSION,
+                firmware: get_firmware_version(),
+                name: String::new(),
+                app: String::new(),
+                lobby: 0,
+                channel: DEFAULT_CHANNEL,
+                key: [0; KEY_SIZE],
+            },
+            same_app: false,
+            channel_set: false,
+            sweep: None,
+            discovered: Discovered::default(),
+            peers: Peers::default(),
+            reassembler: Reassembler::default(),
+            seq: SeqTracker::default(),
+            streams: Streams::default(),
+            next_msg_id: 0,
+            staged: Vec::new(),
+            large: VecDeque::new(),
+            reading: None,
         };
+        actor.beacon.key = actor.pairing.public();
         _ = actor.stop();
+        if let Err(err) = actor.init_pad() {
+            println!("error: {}", ErrPrinter(err));
+            println!("touchpad is not available, only buttons will work");
+        }
         actor
     }
 
     pub fn handle(&mut self, req: Request) -> RespBuf<'_> {
+        self.power.activity(now_ms());
         match self.handle_inner(req) {
             Ok(resp) => resp,
             Err(err) => {
@@ -83,6 +216,77 @@ impl<'a> Actor<'a> {
         }
     }
 
+    pub fn handle_ext(&mut self, req: proto::Request) -> RespBuf<'_> {
+        self.power.activity(now_ms());
+        match self.handle_ext_inner(req) {
+            Ok(resp) => RespBuf::Ext(resp),
+            Err(err) => {
+                let err = alloc::format!("{}", ErrPrinter(err));
+                println!("error: {err:?}");
+                RespBuf::Err(err)
+            }
+        }
+    }
+
+    /// Do background work when there are no requests from the main chip.
+    pub fn tick(&mut self) {
+        self.power.maybe_deep_sleep();
+        let buttons = self.read_buttons();
+        self.idle.buttons(buttons, now_ms());
+        self.latency.buttons(buttons, now_us());
+        if let Some(action) = self.combos.update(buttons, now_ms()) {
+            self.run_action(action);
+        }
+        if self.sampler.due(now_us()) {
+            let (pos, buttons) = self.read_input();
+            self.frame.add(buttons, pos);
+        } else if self.idle_poll.due(now_us()) {
+            let touched = self
+                .read_touch()
+                .is_some_and(|(touch, _)| touch.pos().is_some());
+            self.idle.touch(touched, now_ms());
+            self.latency.touch(touched, now_us());
+        }
+        self.flush_streams();
+        retries::poll();
+        if let Err(err) = self.sweep_channels() {
+            println!("error: {}", ErrPrinter(err));
+        }
+        if self.idle.is_idle(now_ms()) {
+            self.flags |= proto::FLAG_IDLE;
+        } else {
+            self.flags &= !proto::FLAG_IDLE;
+        }
+        if self.power.is_idle(now_ms()) && self.can_sleep() {
+            self.power.light_sleep();
+        }
+    }
+
+    /// Check if there is nothing that would break if the chip enters light sleep.
+    fn can_sleep(&mut self) -> bool {
+        !self.sampler.is_enabled() && self.wifi.status() == Status::Stopped
+    }
+
+    fn run_action(&mut self, action: Action) {
+        match action {
+            Action::Reboot => {
+                println!("combo: reboot");
+                software_reset();
+            }
+            Action::FactoryPartition => {
+                println!("combo: switching to factory partition");
+                match self.switch_partition(0) {
+                    Ok(()) => software_reset(),
+                    Err(err) => println!("error: {}", ErrPrinter(err)),
+                }
+            }
+            Action::Recovery => {
+                println!("combo: recovery mode");
+                self.flags |= proto::FLAG_RECOVERY;
+            }
+        }
+    }
+
     fn handle_inner<'b>(&mut self, req: Request) -> Result<RespBuf<'b>> {
         let response = match req {
             Request::NetStart => {
@@ -98,15 +302,23 @@ impl<'a> Actor<'a> {
                 Response::NetLocalAddr(addr)
             }
             Request::NetAdvertise => {
-                Self::advertise();
+                self.advertise()?;
                 Response::NetAdvertised
             }
             Request::NetRecv => match self.recv()? {
+                Some((addr, msg)) if msg.len() > MAX_INCOMING => {
+                    self.store_large(addr, msg);
+                    Response::NetNoIncoming
+                }
                 Some((addr, msg)) => return Ok(RespBuf::Incoming(addr, msg)),
                 None => Response::NetNoIncoming,
             },
             Request::NetSend(addr, data) => {
-                Self::send(addr, data);
+                // The main chip doesn't wait for a response to NetSend,
+                // so the failure is reported only through NetSendStatus.
+                if let Err(err) = self.send(addr, Class::Reliable, data) {
+                    println!("error: {}", ErrPrinter(err));
+                }
                 Response::NetSent
             }
             Request::NetSendStatus(addr) => {
@@ -114,8 +326,9 @@ impl<'a> Actor<'a> {
                 Response::NetSendStatus(status)
             }
             Request::ReadInput => {
-                let input = self.read_input()?;
-                Response::Input(input.0, input.1)
+                let (pad, buttons) = self.read_input();
+                self.latency.report(now_us());
+                Response::Input(pad, ButtonMap::to_levels(buttons))
             }
             Request::FirmwareInfo => {
                 let version = get_firmware_version();
@@ -170,25 +383,408 @@ impl<'a> Actor<'a> {
         Ok(RespBuf::Response(response))
     }
 
-    fn read_input(&mut self) -> Result<RawInput> {
-        let buttons = u8::from(self.buttons.s.is_high())
+    #[allow(clippy::too_many_lines)]
+    fn handle_ext_inner(&mut self, req: proto::Request) -> Result<proto::Response> {
+        use proto::Request as Req;
+        use proto::Response as Resp;
+        let response = match req {
+            Req::ReadTouch => {
+                let Some((touch, stick)) = self.read_touch() else {
+                    bail!("touchpad is not available");
+                };
+                Resp::Touch(touch, stick)
+            }
+            Req::SetTouchThreshold(threshold) => {
+                if !threshold.is_valid() {
+                    bail!("invalid touch threshold");
+                }
+                self.touch.threshold = threshold;
+                Resp::TouchThresholdSet
+            }
+            Req::GetPadConfig => Resp::PadConfig(self.settings.pad),
+            Req::SetPadConfig(config) => {
+                let prev = self.settings.pad;
+                self.settings.pad = config;
+                // Don't persist a config the touchpad doesn't accept,
+                // and bring the touchpad back to the previous one.
+                if let Err(err) = self.init_pad() {
+                    self.settings.pad = prev;
+                    _ = self.init_pad();
+                    return Err(err);
+                }
+                self.settings.save(&mut self.flash)?;
+                Resp::PadConfigSet
+            }
+            Req::GetPadHealth => Resp::PadHealth(self.health),
+            Req::SetStickConfig(config) => {
+                if !config.is_valid() {
+                    bail!("invalid stick config");
+                }
+                self.stick.config = config;
+                Resp::StickConfigSet
+            }
+            Req::SetFilter(config) => {
+                if !config.is_valid() {
+                    bail!("invalid filter config");
+                }
+                self.smoother.set_config(config);
+                Resp::FilterSet
+            }
+            Req::SetCombo(index, combo) => {
+                if !self.combos.set(usize::from(index), combo) {
+                    bail!("combo index is out of range");
+                }
+                Resp::ComboSet
+            }
+            Req::GetFlags => Resp::Flags(self.flags),
+            Req::SetSampleRate(rate) => {
+                self.sampler.set_rate(rate);
+                self.frame.take();
+                Resp::SampleRateSet
+            }
+            Req::ReadFrame => {
+                let now = now_us();
+                let (age, edge_age) = self.latency.ages(now);
+                self.latency.report(now);
+                Resp::Frame(self.frame.take(), age, edge_age)
+            }
+            Req::StartRecording => {
+                self.replay.record(now_ms());
+                Resp::RecordingStarted
+            }
+            Req::StartPlayback => {
+                self.replay.play(now_ms());
+                Resp::PlaybackStarted
+            }
+            Req::StopReplay => {
+                self.replay.stop();
+                Resp::ReplayStopped
+            }
+            Req::ReadRecording(offset) => {
+                let events = self.replay.recorded(usize::from(offset));
+                #[expect(clippy::cast_possible_truncation)]
+                let total = self.replay.total() as u16;
+                let n = events.len().min(proto::MAX_EVENTS);
+                Resp::Recording(total, self.replay.dropped(), events[..n].to_vec())
+            }
+            Req::UploadScript(offset, events) => {
+                if !self.replay.upload(usize::from(offset), &events) {
+                    bail!("invalid script offset or the script is too long");
+                }
+                Resp::ScriptUploaded
+            }
+            Req::GetButtonMap => Resp::ButtonMap(self.settings.buttons),
+            Req::SetButtonMap(map) => {
+                self.settings.buttons = map;
+                self.settings.save(&mut self.flash)?;
+                Resp::ButtonMapSet
+            }
+            Req::SetLatencyMode(measuring) => {
+                self.latency.set_measuring(measuring);
+                Resp::LatencyModeSet
+            }
+            Req::GetLatency => Resp::Latency(self.latency.stats()),
+            Req::SetPowerConfig(config) => {
+                self.power.config = config;
+                Resp::PowerConfigSet
+            }
+            Req::GetPowerStatus => {
+                let power = &self.power;
+                Resp::PowerStatus(power.config, power.sleeps, power.wake_reason)
+            }
+            Req::DeepSleep => {
+                self.power.request_deep_sleep()?;
+                Resp::DeepSleepScheduled
+            }
+            Req::SetIdleTimeout(timeout) => {
+                self.idle.set_timeout(timeout, now_ms());
+                let rate = if timeout == 0 { 0 } else { IDLE_POLL_RATE };
+                self.idle_poll.set_rate(rate);
+                Resp::IdleTimeoutSet
+            }
+            Req::GetCapabilities => Resp::Capabilities(self.capabilities()),
+            Req::ReadInputExt => {
+                let input = self.read_input_ext();
+                self.latency.report(now_us());
+                Resp::InputExt(input)
+            }
+            Req::SetAxisCalibration(index, calibration) => {
+                if !calibration.is_valid() {
+                    bail!("invalid axis calibration");
+                }
+                let Some(axis) = self.buttons.axes.get_mut(usize::from(index)) else {
+                    bail!("axis index is out of range");
+                };
+                axis.calibration = calibration;
+                Resp::AxisCalibrationSet
+            }
+            Req::SetPairingRequired(required) => {
+                self.pairing.required = required;
+                if required {
+                    self.remove_unencrypted()?;
+                }
+                Resp::PairingRequiredSet
+            }
+            Req::ListPairings => Resp::Pairings(self.pairing.pending()),
+            Req::ConfirmPairing(addr) => {
+                self.confirm_pairing(addr)?;
+                Resp::PairingConfirmed
+            }
+            Req::RejectPairing(addr) => {
+                if !self.pairing.reject(addr) {
+                    bail!("no pending pairing with the peer");
+                }
+                Resp::PairingRejected
+            }
+            Req::SetBeacon(info) => {
+                self.beacon.name = info.name;
+                self.beacon.app = info.app;
+                self.beacon.lobby = info.lobby;
+                self.same_app = info.same_app;
+                Resp::BeaconSet
+            }
+            Req::GetDiscovered(index) => {
+                let total = u8::try_from(self.discovered.len()).unwrap_or(u8::MAX);
+                let device = self.discovered.get(usize::from(index)).cloned();
+                Resp::Discovered(total, device)
+            }
+            Req::GetPeer(index) => {
+                let total = u8::try_from(self.peers.len()).unwrap_or(u8::MAX);
+                Resp::Peer(total, self.peers.get(usize::from(index)))
+            }
+            Req::RemovePeer(addr) => {
+                self.remove_peer(addr)?;
+                Resp::PeerRemoved
+            }
+            Req::SetAccess(addr, access) => {
+                if !self.peers.set_access(addr, access) {
+                    bail!("access list is full");
+                }
+                // Adding a device to the allow list blocks all devices not in it.
+                for peer in self.registered_peers() {
+                    if !self.peers.is_allowed(&peer.peer_address) {
+                        self.remove_peer(peer.peer_address)?;
+                    }
+                }
+                Resp::AccessSet
+            }
+            Req::Send(addr, data) => Resp::Queued(self.send(addr, Class::Reliable, &data)?),
+            Req::SendClass(addr, class, data) => {
+                let Ok(class) = Class::try_from(class) else {
+                    bail!("unknown delivery class");
+                };
+                Resp::Queued(self.send(addr, class, &data)?)
+            }
+            Req::SetRetryPolicy(policy) => {
+                if !policy.is_valid() {
+                    bail!("invalid retry policy");
+                }
+                retries::set_policy(policy);
+                Resp::RetryPolicySet
+            }
+            Req::GetRetryPolicy => Resp::RetryPolicy(retries::get_policy()),
+            Req::SetChannel(channel) => {
+                self.set_channel(channel)?;
+                Resp::ChannelSet
+            }
+            Req::PickChannel => {
+                // The scan switches channels and would drop ESP-NOW frames.
+                if self.wifi.status() != Status::Stopped {
+                    bail!("cannot scan channels while the network is running");
+                }
+                let congestion = self.wifi.scan_channels()?;
+                let channel = congestion.quietest();
+                self.set_channel(channel)?;
+                Resp::Channel(channel)
+            }
+            Req::GetLinkStats(addr) => Resp::LinkStats(self.seq.stats(addr)),
+            Req::OpenStream(addr) => {
+                if !self.manager.peer_exists(&addr) {
+                    bail!("unknown peer");
+                }
+                if !self.streams.open(addr) {
+                    bail!("too many open streams");
+                }
+                Resp::StreamOpened
+            }
+            Req::CloseStream(addr) => {
+                self.streams.close(addr);
+                Resp::StreamClosed
+            }
+            Req::StreamSend(addr, data) => {
+                let Some(stream) = self.streams.get_mut(addr) else {
+                    bail!("stream is not open");
+                };
+                if !stream.write(&data) {
+                    bail!("stream is full or broken");
+                }
+                Resp::StreamQueued
+            }
+            Req::GetStreamStatus(addr) => {
+                let maybe_stream = self.streams.get(addr);
+                Resp::StreamStatus(maybe_stream.map(Stream::status))
+            }
+            Req::StageMessage(offset, chunk) => {
+                if offset == 0 {
+                    self.staged.clear();
+                }
+                let offset = usize::from(offset);
+                if offset != self.staged.len() || offset + chunk.len() > MAX_MESSAGE {
+                    bail!("invalid message chunk offset");
+                }
+                self.staged.extend_from_slice(&chunk);
+                Resp::MessageStaged
+            }
+            Req::SendStaged(addr) => {
+                let data = core::mem::take(&mut self.staged);
+                Resp::Queued(self.send(addr, Class::Reliable, &data)?)
+            }
+            Req::RecvLarge(offset) => {
+                if offset == 0 {
+                    self.reading = self.large.pop_front();
+                    if self.large.is_empty() {
+                        self.flags &= !proto::FLAG_LARGE_MESSAGE;
+                    }
+                }
+                let Some((addr, msg)) = &self.reading else {
+                    bail!("no big messages received");
+                };
+                let total = u16::try_from(msg.len()).unwrap_or(u16::MAX);
+                let chunk = msg.get(usize::from(offset)..).unwrap_or_default();
+                let chunk = &chunk[..chunk.len().min(LARGE_CHUNK)];
+                Resp::LargeMessage(*addr, total, chunk.to_vec())
+            }
+            Req::GetMessageStatus(id) => {
+                let status = match retries::get_message_status(id) {
+                    SendStatus::Empty => (0, 0),
+                    SendStatus::Sending(attempts) => (1, attempts),
+                    SendStatus::Delivered(attempts) => (2, attempts),
+                    SendStatus::Failed => (3, 0),
+                };
+                Resp::MessageStatus(status.0, status.1)
+            }
+            Req::GetIdleTime => {
+                let time = self.idle.idle_for(now_ms());
+                Resp::IdleTime(u32::try_from(time).unwrap_or(u32::MAX))
+            }
+            Req::ClearFlags(flags) => {
+                self.flags &= !flags;
+                Resp::FlagsCleared
+            }
+        };
+        Ok(response)
+    }
+
+    /// Read buttons and touchpad position as seen by the main chip.
+    ///
+    /// If the input is being recorded, the reading is stored. If the playback
+    /// is active, the real input is replaced by the uploaded script.
+    fn read_input(&mut self) -> RawInput {
+        let buttons = self.read_buttons();
+        let pad = self.read_touch().and_then(|(touch, _)| touch.pos());
+        self.idle.buttons(buttons, now_ms());
+        self.idle.touch(pad.is_some(), now_ms());
+        let now = now_us();
+        self.latency.buttons(buttons, now);
+        self.latency.touch(pad.is_some(), now);
+        self.latency.capture(now);
+        let (buttons, pad) = self.replay.process(buttons, pad, now_ms());
+        (pad, buttons)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let buttons = &self.buttons;
+        Capabilities::new(
+            buttons.extra.len(),
+            buttons.axes.len(),
+            self.health.is_ready(),
+        )
+    }
+
+    /// Read all inputs, including extra buttons and analog axes.
+    fn read_input_ext(&mut self) -> InputState {
+        let (pos, buttons) = self.read_input();
+        let caps = self.capabilities();
+        let mut buttons = u32::from(buttons);
+        let extra = usize::from(caps.buttons) - BUTTONS;
+        for (i, pin) in self.buttons.extra.iter().take(extra).enumerate() {
+            // Extra buttons are wired the same way as the built-in ones.
+            if pin.is_low() {
+                buttons |= 1 << (BUTTONS + i);
+            }
+        }
+        let axes = usize::from(caps.axes);
+        let axes = self.buttons.axes.iter_mut().take(axes).map(Axis::read);
+        let (_, edge_age) = self.latency.ages(now_us());
+        InputState {
+            count: caps.buttons,
+            buttons,
+            axes: axes.collect(),
+            pos,
+            edge_age,
+        }
+    }
+
+    /// Read the bitmask of pressed logical buttons.
+    fn read_buttons(&self) -> u8 {
+        let levels = u8::from(self.buttons.s.is_high())
             | u8::from(self.buttons.e.is_high()) << 1
             | u8::from(self.buttons.w.is_high()) << 2
             | u8::from(self.buttons.n.is_high()) << 3
             | u8::from(self.buttons.menu.is_high()) << 4;
+        self.settings.buttons.apply(levels)
+    }
+
+    /// Read the touchpad, classify the reading using the touch threshold,
+    /// smooth the coordinates, and update the virtual stick.
+    ///
+    /// Returns None if the touchpad is not available. After a few failed reads
+    /// in a row, the touchpad is marked as not available and then periodically
+    /// re-initialized with an increasing delay between attempts.
+    fn read_touch(&mut self) -> Option<(Touch, StickState)> {
+        if !self.health.is_ready() {
+            if !self.health.should_retry(now_ms()) {
+                return None;
+            }
+            if let Err(err) = self.init_pad() {
+                println!("error: {}", ErrPrinter(err));
+                return None;
+            }
+        }
         match self.pad.read_absolute() {
-            Ok(touch) => {
-                let pad = if touch.touched() {
-                    Some((touch.x, touch.y))
+            Ok(raw) => {
+                self.health.read_ok();
+                let (x, y) = self.settings.pad.orient(raw.x, raw.y);
+                let mut touch = self.touch.update(x, y, raw.z);
+                if touch.state == TouchState::Touched {
+                    (touch.x, touch.y) = self.smoother.update(touch.x, touch.y, now_us());
                 } else {
-                    None
-                };
-                Ok((pad, buttons))
+                    self.smoother.reset();
+                }
+                let stick = self.stick.update(touch.pos(), self.settings.pad.center());
+                Some((touch, stick))
+            }
+            Err(err) => {
+                if self.health.read_failed(now_ms()) {
+                    println!("error: spi: {}", convert_error(err));
+                    println!("touchpad is down, only buttons will work");
+                }
+                None
             }
-            Err(err) => bail!("spi: {}", convert_error(err)),
         }
     }
 
+    /// Configure the touchpad using the current settings.
+    fn init_pad(&mut self) -> Result<()> {
+        let config = self.settings.pad;
+        let res = self.pad.init(config.gain, config.filter);
+        self.health.init_done(res.is_ok(), now_ms());
+        if let Err(err) = res {
+            bail!("init touchpad: {}", convert_error(err));
+        }
+        Ok(())
+    }
+
     fn get_partition(&mut self) -> Result<u8> {
         let mut buf = [0u8; esp_bootloader_esp_idf::partitions::PARTITION_TABLE_MAX_LEN];
         let parts = read_partition_table(&mut self.flash, &mut buf)?;
@@ -232,7 +828,16 @@ impl<'a> Actor<'a> {
 
     fn start(&mut self) -> Result<()> {
         self.wifi.start()?;
-        self.manager.set_channel(6)?;
+        let channel = if self.channel_set {
+            self.beacon.channel
+        } else {
+            let sweep = Sweep::new(now_ms());
+            let channel = sweep.channel();
+            self.sweep = Some(sweep);
+            channel
+        };
+        self.manager.set_channel(channel)?;
+        self.manager.set_pmk(&pairing::PMK)?;
         // self.manager.set_rate(WifiPhyRate::Rate54m)?;
         retries::start()?;
         Ok(())
@@ -240,57 +845,294 @@ impl<'a> Actor<'a> {
 
     fn stop(&mut self) -> Result<()> {
         self.wifi.stop()?;
+        self.sweep = None;
         while let Ok(peer) = self.manager.fetch_peer(true) {
             self.manager.remove_peer(&peer.peer_address)?;
         }
+        self.pairing.clear();
+        self.discovered.clear();
+        self.peers.clear();
+        self.reassembler.clear();
+        self.seq.clear(new_epoch());
+        self.streams.clear();
+        self.large.clear();
+        self.reading = None;
+        self.flags &= !proto::FLAG_LARGE_MESSAGE;
         retries::stop()?;
         Ok(())
     }
 
+    /// Switch to the channel picked by the main chip and stop following host beacons.
+    fn set_channel(&mut self, channel: u8) -> Result<()> {
+        if !channel::is_valid(channel) {
+            bail!("invalid channel");
+        }
+        self.channel_set = true;
+        self.sweep = None;
+        self.switch_channel(channel)
+    }
+
+    /// Switch to the channel and announce it in beacons.
+    ///
+    /// If the network is not running, the channel is applied when it starts.
+    fn switch_channel(&mut self, channel: u8) -> Result<()> {
+        self.beacon.channel = channel;
+        if self.wifi.status() != Status::Stopped {
+            self.manager.set_channel(channel)?;
+        }
+        Ok(())
+    }
+
+    /// Hop to the next channel if still looking for a host.
+    fn sweep_channels(&mut self) -> Result<()> {
+        let Some(sweep) = &mut self.sweep else {
+            return Ok(());
+        };
+        let Some(channel) = sweep.poll(now_ms()) else {
+            return Ok(());
+        };
+        if sweep.is_done() {
+            // Nobody found, stay on the default channel and announce it.
+            self.sweep = None;
+            return self.switch_channel(channel);
+        }
+        self.manager.set_channel(channel)?;
+        Ok(())
+    }
+
+    /// Join the channel announced in the beacon, unless the channel is set by the main chip.
+    fn follow_beacon(&mut self, beacon: &Beacon) -> Result<()> {
+        if self.channel_set || !channel::is_valid(beacon.channel) {
+            return Ok(());
+        }
+        // While sweeping, the radio might be tuned to another channel than announced.
+        let sweeping = self.sweep.take().is_some();
+        if sweeping || beacon.channel != self.beacon.channel {
+            self.switch_channel(beacon.channel)?;
+        }
+        Ok(())
+    }
+
     fn local_addr() -> Addr {
         esp_radio::wifi::sta_mac()
     }
 
-    fn advertise() {
-        Self::send(BROADCAST_ADDRESS, b"HELLO");
+    fn advertise(&self) -> Result<()> {
+        // Beacons are not data frames, so they are sent as is.
+        // Broadcasts are never acked, so retrying them only wastes airtime.
+        if retries::send(BROADCAST_ADDRESS, Class::Unreliable, &self.beacon.encode()).is_err() {
+            bail!("send queue is full");
+        }
+        Ok(())
     }
 
-    fn recv(&self) -> Result<Option<(Addr, Box<[u8]>)>> {
+    fn recv(&mut self) -> Result<Option<(Addr, Box<[u8]>)>> {
+        // Several stream segments can become ready when a missing one arrives.
+        if let Some(msg) = self.streams.read() {
+            return Ok(Some(msg));
+        }
         let Some(packet) = self.receiver.receive() else {
             return Ok(None);
         };
 
-        let known_peer = self.manager.peer_exists(&packet.info.src_address);
-        if !known_peer {
-            if packet.data() == b"HELLO" {
+        let addr = packet.info.src_address;
+        if !self.peers.is_allowed(&addr) {
+            return Ok(None);
+        }
+        let rssi = i8::try_from(packet.info.rx_control.rssi).unwrap_or(i8::MIN);
+        let peer = self.manager.get_peer(&addr).ok();
+        if let Some(beacon) = Beacon::decode(packet.data()) {
+            if !self.beacon.accepts(&beacon, self.same_app) {
+                return Ok(None);
+            }
+            self.follow_beacon(&beacon)?;
+            let encrypted = peer.as_ref().is_some_and(|peer| peer.encrypt);
+            if !encrypted {
+                let msgs = self.pairing.offer(addr, beacon.key, new_nonce(), now_ms());
+                send_pairing(addr, &msgs);
+            }
+            self.discovered.insert(addr, beacon);
+            if peer.is_none() {
+                if self.pairing.required {
+                    return Ok(None);
+                }
                 let peer = PeerInfo {
-                    peer_address: packet.info.src_address,
+                    peer_address: addr,
                     lmk: None,
                     channel: None,
                     encrypt: false,
                     interface: EspNowWifiInterface::Sta,
                 };
                 self.manager.add_peer(peer)?;
-            } else {
-                return Ok(None);
             }
+            self.peers.seen(addr, now_ms(), rssi, encrypted);
+            // The beacon details are available with an extension request.
+            return Ok(Some((addr, HELLO.into())));
+        }
+        // Pairing messages are broadcast and come from peers that aren't added yet.
+        if let Some((to, msg)) = Message::decode(packet.data()) {
+            if to == Self::local_addr() {
+                let msgs = self.pairing.receive(addr, msg, new_nonce());
+                send_pairing(addr, &msgs);
+            }
+            return Ok(None);
         }
+        let Some(peer) = peer else {
+            return Ok(None);
+        };
+        // The peer might have been added before the pairing was required.
+        if self.pairing.required && !peer.encrypt {
+            return Ok(None);
+        }
+        self.peers.seen(addr, now_ms(), rssi, peer.encrypt);
 
-        let data = packet.data();
-        let data = data.to_vec().into_boxed_slice();
-        Ok(Some((packet.info.src_address, data)))
+        let Some((stamp, frame)) = seq::unstamp(packet.data()) else {
+            return Ok(None);
+        };
+        if !self.seq.accept(addr, stamp) {
+            return Ok(None);
+        }
+        if let Some(packet) = Packet::parse(frame) {
+            self.streams.receive(addr, &packet);
+            return Ok(self.streams.read());
+        }
+        let data = match Frame::parse(frame) {
+            Some(Frame::Whole(data)) => data.into(),
+            Some(Frame::Fragment(fragment)) => {
+                let maybe_msg = self.reassembler.push(addr, &fragment, now_ms());
+                let Some(msg) = maybe_msg else {
+                    return Ok(None);
+                };
+                msg.into_boxed_slice()
+            }
+            None => return Ok(None),
+        };
+        Ok(Some((addr, data)))
+    }
+
+    /// Keep the received message that is too big for `NetRecv`
+    /// until the main chip reads it using `RecvLarge`.
+    fn store_large(&mut self, addr: Addr, msg: Box<[u8]>) {
+        if self.large.len() >= MAX_LARGE {
+            println!("error: dropping a big message, too many are waiting");
+            self.large.pop_front();
+        }
+        self.large.push_back((addr, msg));
+        self.flags |= proto::FLAG_LARGE_MESSAGE;
+    }
+
+    /// Register the peer with the LMK derived during pairing, so that ESP-NOW encrypts the traffic.
+    fn confirm_pairing(&mut self, addr: Addr) -> Result<()> {
+        let Some(lmk) = self.pairing.confirm(addr) else {
+            bail!("no pending pairing with the peer");
+        };
+        let peer = PeerInfo {
+            peer_address: addr,
+            lmk: Some(lmk),
+            channel: None,
+            encrypt: true,
+            interface: EspNowWifiInterface::Sta,
+        };
+        if self.manager.peer_exists(&addr) {
+            self.manager.modify_peer(peer)?;
+        } else {
+            self.manager.add_peer(peer)?;
+        }
+        self.peers.set_encrypted(addr);
+        Ok(())
+    }
+
+    /// All peers added to ESP-NOW.
+    fn registered_peers(&mut self) -> Vec<PeerInfo> {
+        let mut peers = Vec::new();
+        let mut from_head = true;
+        while let Ok(peer) = self.manager.fetch_peer(from_head) {
+            from_head = false;
+            peers.push(peer);
+        }
+        peers
+    }
+
+    /// Disconnect from all peers that don't have a confirmed pairing.
+    fn remove_unencrypted(&mut self) -> Result<()> {
+        for peer in self.registered_peers() {
+            if !peer.encrypt {
+                self.remove_peer(peer.peer_address)?;
+            }
+        }
+        Ok(())
     }
 
-    fn send(addr: Addr, data: &[u8]) {
-        retries::send(addr, data);
+    /// Disconnect from the peer and forget everything about it.
+    fn remove_peer(&mut self, addr: Addr) -> Result<()> {
+        if self.manager.peer_exists(&addr) {
+            self.manager.remove_peer(&addr)?;
+        }
+        self.peers.remove(addr);
+        self.seq.remove(addr);
+        self.streams.close(addr);
+        self.pairing.reject(addr);
+        Ok(())
+    }
+
+    /// Queue the message for sending, splitting it into fragments if needed.
+    ///
+    /// Only reliable messages can be split: a lost fragment of any other
+    /// message would make the whole message lost.
+    ///
+    /// Returns the ID of the last queued frame.
+    fn send(&mut self, addr: Addr, class: Class, data: &[u8]) -> Result<u16> {
+        let msg_id = self.next_msg_id;
+        self.next_msg_id = msg_id.wrapping_add(1);
+        let Some(frames) = frag::split(msg_id, data) else {
+            bail!("message is too big");
+        };
+        if class != Class::Reliable && frames.len() > 1 {
+            bail!("message is too big for the delivery class");
+        }
+        if class == Class::Reliable && retries::queued(addr) + frames.len() > retries::QUEUE_SIZE {
+            bail!("send queue is full");
+        }
+        if frames.len() > retries::available() {
+            bail!("not enough memory to queue the message");
+        }
+        let mut id = 0;
+        for frame in frames {
+            let frame = seq::stamp(self.seq.peek(addr), &frame);
+            let Ok(frame_id) = retries::send(addr, class, &frame) else {
+                bail!("send queue is full");
+            };
+            self.seq.advance(addr);
+            id = frame_id;
+        }
+        Ok(id)
+    }
+
+    /// Send new stream segments, retransmissions, and acks.
+    ///
+    /// Streams do their own retransmissions, so the frames are not retried.
+    /// If the send queue is full, the stream tries the frame again on the next tick.
+    fn flush_streams(&mut self) {
+        let tracker = &mut self.seq;
+        self.streams.poll(now_ms(), |addr, frame| {
+            let frame = seq::stamp(tracker.peek(addr), frame);
+            // A frame that didn't fit into the queue doesn't use up a sequence number.
+            let sent = retries::send(addr, Class::Unreliable, &frame).is_ok();
+            if sent {
+                tracker.advance(addr);
+            }
+            sent
+        });
     }
 }
 
-const fn convert_error(
-    value: embedded_hal_bus::spi::DeviceError<esp_hal::spi::Error, Infallible>,
-) -> &'static str {
+const fn convert_error(value: PadError) -> &'static str {
     use esp_hal::dma::DmaError;
-    let embedded_hal_bus::spi::DeviceError::Spi(err) = value;
+    let err = match value {
+        pinnacle::Error::Spi(embedded_hal_bus::spi::DeviceError::Spi(err)) => err,
+        pinnacle::Error::Timeout => return "touchpad timeout",
+        pinnacle::Error::NotFound => return "touchpad not found",
+    };
     match err {
         esp_hal::spi::Error::DmaError(err) => match err {
             DmaError::InvalidAlignment(_) => "dmi: invalid alignment",
@@ -314,6 +1156,43 @@ const fn convert_error(
     }
 }
 
+/// Generate a random nonce for pairing.
+fn new_nonce() -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    Rng::new().read(&mut nonce);
+    nonce
+}
+
+/// Generate a random epoch for sequence numbers.
+fn new_epoch() -> u16 {
+    let mut epoch = [0u8; 2];
+    Rng::new().read(&mut epoch);
+    u16::from_le_bytes(epoch)
+}
+
+/// Send pairing messages to the peer.
+///
+/// Errors are ignored: lost messages are sent again on the next beacon from the peer.
+fn send_pairing(to: Addr, msgs: &[Message]) {
+    for msg in msgs {
+        _ = retries::send(BROADCAST_ADDRESS, Class::Unreliable, &msg.encode(to));
+    }
+}
+
+/// Milliseconds since boot.
+pub(crate) fn now_ms() -> u64 {
+    esp_hal::time::Instant::now()
+        .duration_since_epoch()
+        .as_millis()
+}
+
+/// Microseconds since boot.
+fn now_us() -> u64 {
+    esp_hal::time::Instant::now()
+        .duration_since_epoch()
+        .as_micros()
+}
+
 fn get_firmware_version() -> (u8, u8, u8) {
     let major: u8 = env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap();
     let minor: u8 = env!("CARGO_PKG_VERSION_MINOR").parse().unwrap();
@@ -0,0 +1,34 @@
+//! Analog axes read using the ESP32-S3 ADC.
+use crate::input::AxisSource;
+use alloc::rc::Rc;
+use core::cell::RefCell;
+use esp_hal::{
+    analog::adc::{Adc, AdcChannel, AdcPin},
+    peripherals::ADC1,
+    Blocking,
+};
+
+/// The ADC shared by all axes connected to it.
+pub type SharedAdc<'a> = Rc<RefCell<Adc<'a, ADC1<'a>, Blocking>>>;
+
+/// An analog axis connected to an ADC1 pin.
+///
+/// The pin must be enabled in the ADC config before the ADC is created.
+pub struct AdcAxis<'a, P> {
+    adc: SharedAdc<'a>,
+    pin: AdcPin<P, ADC1<'a>>,
+}
+
+impl<'a, P> AdcAxis<'a, P> {
+    #[must_use]
+    pub const fn new(adc: SharedAdc<'a>, pin: AdcPin<P, ADC1<'a>>) -> Self {
+        Self { adc, pin }
+    }
+}
+
+impl<P: AdcChannel> AxisSource for AdcAxis<'_, P> {
+    fn read(&mut self) -> Option<u16> {
+        let mut adc = self.adc.borrow_mut();
+        Some(adc.read_blocking(&mut self.pin))
+    }
+}
@@ -1,5 +1,4 @@
 #![no_std]
-#![feature(linked_list_retain)]
 #![deny(clippy::all, clippy::pedantic, clippy::nursery)]
 #![allow(
     clippy::missing_errors_doc,
@@ -12,15 +11,28 @@
 extern crate alloc;
 
 mod actor;
+#[cfg(feature = "adc")]
+pub mod analog;
 mod error;
 mod net;
+pub mod power;
 pub mod retries;
+mod settings;
 mod v1;
 mod v2;
 mod wifi;
 
+pub use firefly_io_core::{
+    backoff, beacon, channel, filter, frag, idle, input, latency, layout, pairing, peers, proto,
+    replay, ring, sampler, seq, stick, stream, touch,
+};
+use firefly_io_core::{combo, health, pinnacle};
+
 pub use actor::*;
 pub use error::ErrPrinter;
 pub(crate) use net::*;
+pub use pinnacle::Pinnacle;
+pub use power::Power;
+pub use settings::Settings;
 pub use v1::*;
 pub use v2::*;
@@ -1,10 +1,57 @@
-use crate::RespBuf;
+use crate::{proto, Actor, ErrPrinter, RespBuf};
 use anyhow::{Context, Result};
+use embedded_io::Read;
 use esp_backtrace as _;
 use esp_hal::{uart::Uart, Blocking};
 use esp_println::println;
 use firefly_types::{spi::*, Encode};
 
+/// Read requests from UART, handle them, and write responses back.
+///
+/// While waiting for requests, runs the background work of the actor.
+pub fn serve(uart: &mut Uart<'_, Blocking>, actor: &mut Actor<'_>) -> Result<()> {
+    let buf = &mut [0u8; 300];
+    loop {
+        actor.tick();
+        if !uart.read_ready() {
+            continue;
+        }
+
+        // read request size
+        uart.read(&mut buf[..1]).context("read request size")?;
+        let size = usize::from(buf[0]);
+        if size == 0 {
+            // An empty request is sent by the main chip to wake us up from light sleep.
+            continue;
+        }
+
+        // read request payload
+        // TODO(@orsinium): don't unwrap
+        uart.read_exact(&mut buf[..size]).unwrap();
+        let payload = &buf[..size];
+        // A request that can't be decoded (for example, sent by a newer main chip firmware)
+        // is reported back instead of stopping the loop.
+        let resp = if proto::is_ext(payload) {
+            match proto::Request::decode(payload).context("decode ext request") {
+                Ok(req) => actor.handle_ext(req),
+                Err(err) => decode_error(err),
+            }
+        } else {
+            match Request::decode(payload).context("decode request") {
+                Ok(req) => actor.handle(req),
+                Err(err) => decode_error(err),
+            }
+        };
+        send_resp_buf(uart, buf, resp)?;
+    }
+}
+
+fn decode_error<'a>(err: anyhow::Error) -> RespBuf<'a> {
+    let err = alloc::format!("{}", ErrPrinter(err));
+    println!("error: {err:?}");
+    RespBuf::Err(err)
+}
+
 /// Serialize response and write it into UART.
 pub fn send_resp_buf(uart: &mut Uart<'_, Blocking>, buf: &mut [u8], resp: RespBuf) -> Result<()> {
     match resp {
@@ -23,6 +70,9 @@ pub fn send_resp_buf(uart: &mut Uart<'_, Blocking>, buf: &mut [u8], resp: RespBu
             let resp = Response::TcpChunk(&data);
             send_resp(uart, buf, resp)?;
         }
+        RespBuf::Ext(resp) => {
+            send_ext_resp(uart, buf, &resp)?;
+        }
         RespBuf::Scan(ssids) => {
             let ssids = [
                 ssids[0].as_str(),
@@ -59,3 +109,21 @@ fn send_resp(uart: &mut Uart<'_, Blocking>, buf: &mut [u8], resp: Response<'_>)
     uart.write(buf).context("write response")?;
     Ok(())
 }
+
+fn send_ext_resp(
+    uart: &mut Uart<'_, Blocking>,
+    buf: &mut [u8],
+    resp: &proto::Response,
+) -> Result<()> {
+    let tail = &mut buf[1..=usize::from(u8::MAX)];
+    let Ok(size) = resp.encode_buf(tail).map(<[u8]>::len) else {
+        println!("error: response is too big");
+        let resp = Response::Error("response is too big");
+        return send_resp(uart, buf, resp);
+    };
+    #[expect(clippy::cast_possible_truncation)]
+    let head = size as u8;
+    buf[0] = head;
+    uart.write(&buf[..=size]).context("write response")?;
+    Ok(())
+}
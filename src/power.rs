@@ -0,0 +1,133 @@
+//! Power management: light sleep when idle and deep sleep on request.
+//!
+//! The IO chip wakes up from light sleep on UART RX. The bytes that
+//! wake the chip up are lost, so after a period of inactivity the main chip
+//! must send a few zero bytes (empty requests) before the actual request.
+//!
+//! The chip also wakes up periodically to run the background work
+//! (like detecting button combos) and goes back to sleep if still idle.
+use anyhow::{bail, Result};
+use esp_hal::peripherals::LPWR;
+use esp_hal::rtc_cntl::{
+    sleep::{TimerWakeupSource, Uart1WakeupSource},
+    wakeup_cause, Rtc,
+};
+use esp_hal::system::SleepSource;
+use esp_println::println;
+
+pub use firefly_io_core::power::{PowerConfig, WakeReason};
+
+/// How long (in milliseconds) to sleep before waking up to run the background work.
+const TICK_PERIOD: u64 = 50;
+/// How many RX edges on UART are needed to wake up.
+const WAKE_EDGES: u16 = 3;
+/// The timer bit of the `RTC_CNTL_SLP_WAKEUP_CAUSE` register.
+const CAUSE_TIMER: u32 = 1 << 3;
+/// The UART0 and UART1 bits of the `RTC_CNTL_SLP_WAKEUP_CAUSE` register.
+const CAUSE_UART: u32 = (1 << 6) | (1 << 7);
+
+/// Enter deep sleep configured to wake up on the menu button.
+///
+/// The wakeup pin is board-specific, so each board provides its own implementation.
+/// Waking up from deep sleep resets the chip.
+pub type DeepSleep = fn(&mut Rtc<'_>) -> !;
+
+pub struct Power<'a> {
+    rtc: Rtc<'a>,
+    deep_sleep: Option<DeepSleep>,
+    pub config: PowerConfig,
+    /// When the last request from the main chip was received.
+    last_request: u64,
+    /// If deep sleep was requested. It's entered after the response is sent.
+    deep_sleep_requested: bool,
+    /// How many times the chip entered light sleep since boot.
+    pub sleeps: u32,
+    pub wake_reason: WakeReason,
+}
+
+impl<'a> Power<'a> {
+    /// Create the power manager.
+    ///
+    /// Pass None for `deep_sleep` if the board can't wake up from deep sleep on input.
+    #[must_use]
+    pub fn new(rtc: Rtc<'a>, deep_sleep: Option<DeepSleep>) -> Self {
+        let wake_reason = last_wake_reason();
+        println!("wake reason: {}", u8::from(wake_reason));
+        Self {
+            rtc,
+            deep_sleep,
+            config: PowerConfig::default(),
+            last_request: 0,
+            deep_sleep_requested: false,
+            sleeps: 0,
+            wake_reason,
+        }
+    }
+
+    /// Record that a request from the main chip has been received.
+    pub const fn activity(&mut self, now: u64) {
+        self.last_request = now;
+    }
+
+    /// Check if it's time to enter light sleep.
+    #[must_use]
+    pub fn is_idle(&self, now: u64) -> bool {
+        self.config.light_sleep && now >= self.last_request + u64::from(self.config.idle)
+    }
+
+    /// Enter light sleep until a request arrives or it's time for the background work.
+    pub fn light_sleep(&mut self) {
+        let timer = TimerWakeupSource::new(core::time::Duration::from_millis(TICK_PERIOD));
+        // The main chip is connected to UART1 on all boards.
+        let uart = Uart1WakeupSource::new(WAKE_EDGES);
+        self.rtc.sleep_light(&[&timer, &uart]);
+        self.sleeps = self.sleeps.saturating_add(1);
+        self.wake_reason = light_wake_reason();
+    }
+
+    /// Schedule deep sleep after the current response is sent.
+    pub fn request_deep_sleep(&mut self) -> Result<()> {
+        if self.deep_sleep.is_none() {
+            bail!("deep sleep is not supported on this board");
+        }
+        self.deep_sleep_requested = true;
+        Ok(())
+    }
+
+    /// Enter deep sleep if it was requested.
+    pub fn maybe_deep_sleep(&mut self) {
+        if !self.deep_sleep_requested {
+            return;
+        }
+        if let Some(deep_sleep) = self.deep_sleep {
+            println!("entering deep sleep...");
+            deep_sleep(&mut self.rtc);
+        }
+    }
+}
+
+/// The reason of the wakeup from deep sleep that caused the last reset.
+fn last_wake_reason() -> WakeReason {
+    match wakeup_cause() {
+        SleepSource::Undefined => WakeReason::Reset,
+        SleepSource::Ext0 | SleepSource::Ext1 => WakeReason::Button,
+        SleepSource::Timer => WakeReason::Timer,
+        SleepSource::Uart => WakeReason::Uart,
+        _ => WakeReason::Other,
+    }
+}
+
+/// The reason of the wakeup from the light sleep that has just ended.
+///
+/// [`wakeup_cause`] reports only wakeups from deep sleep,
+/// so the cause is read from the RTC register directly.
+fn light_wake_reason() -> WakeReason {
+    let cause = LPWR::regs().slp_wakeup_cause().read().wakeup_cause().bits();
+    if cause & CAUSE_UART != 0 {
+        WakeReason::Uart
+    } else if cause & CAUSE_TIMER != 0 {
+        WakeReason::Timer
+    } else {
+        WakeReason::Other
+    }
+}
@@ -1,31 +1,16 @@
+use crate::actor::now_ms;
+use crate::backoff::RetryPolicy;
 use crate::*;
-use alloc::boxed::Box;
-use alloc::collections::LinkedList;
 use core::cell::RefCell;
 use critical_section::Mutex;
-use esp_hal::delay;
+use esp_hal::rng::Rng;
 use esp_radio::esp_now::EspNowError;
 use esp_wifi_sys::include::*;
+pub use firefly_io_core::outbox::{Class, QueueFull, QUEUE_SIZE};
+use firefly_io_core::outbox::{Event, Outbox, MAX_DATA};
 use firefly_types::spi::SendStatus;
 
-const MAX_RETRIES: u8 = 15;
-
-struct Msg {
-    addr: Addr,
-    data: Box<[u8]>,
-    attempts: u8,
-}
-
-struct State {
-    addr: Addr,
-    status: SendStatus,
-}
-
-type Msgs = LinkedList<Msg>;
-type States = LinkedList<State>;
-
-static PENDING: Mutex<RefCell<Msgs>> = Mutex::new(RefCell::new(Msgs::new()));
-static STATES: Mutex<RefCell<States>> = Mutex::new(RefCell::new(States::new()));
+static OUTBOX: Mutex<RefCell<Outbox>> = Mutex::new(RefCell::new(Outbox::new()));
 
 /// Register the send callback.
 pub fn start() -> Result<(), EspNowError> {
@@ -33,112 +18,130 @@ pub fn start() -> Result<(), EspNowError> {
     parse_error_code(code)
 }
 
-/// Unregister the send callback, clear pending messages and delivery states.
+/// Unregister the send callback, clear pending messages,
+/// and reset the retry policy.
 pub fn stop() -> Result<(), EspNowError> {
     let code = unsafe { esp_now_register_send_cb(None) };
-    critical_section::with(|cs| {
-        let pending = PENDING.borrow(cs);
-        let mut pending = pending.borrow_mut();
-        pending.clear();
-    });
+    critical_section::with(|cs| OUTBOX.borrow(cs).borrow_mut().clear());
     parse_error_code(code)
 }
 
-/// Send a message with retries.
+/// Queue a message to be sent and return its ID.
 ///
-/// If there is already a pending message for the given peer,
-/// blocks until that message is delivered.
-pub fn send(addr: Addr, data: &[u8]) {
-    while is_pending(addr) {
-        delay::Delay::new().delay_micros(5);
-    }
-    set_status(addr, SendStatus::Sending(0));
-    let code = unsafe { esp_now_send(addr.as_ptr(), data.as_ptr(), data.len()) };
-    if code == 0 {
-        store_pending(addr, data);
-    } else {
-        set_status(addr, SendStatus::Failed);
-    }
+/// Returns immediately. Reliable messages for the same peer are sent
+/// one at a time, in the order they were queued. If there is no room
+/// for the message, it is rejected, the latest status for the peer is set
+/// to failed, and the caller should try again later.
+pub fn send(addr: Addr, class: Class, data: &[u8]) -> Result<u16, QueueFull> {
+    let now = now_ms();
+    let id = critical_section::with(|cs| {
+        let outbox = OUTBOX.borrow(cs);
+        outbox.borrow_mut().enqueue(addr, class, data, now)
+    })?;
+    flush(now);
+    Ok(id)
 }
 
-fn store_pending(addr: Addr, data: &[u8]) {
-    critical_section::with(|cs| {
-        let pending = PENDING.borrow(cs);
-        let mut pending = pending.borrow_mut();
-        pending.push_back(Msg {
-            addr,
-            data: data.into(),
-            attempts: 0,
+/// Handle acks and naks reported by esp-now and send the messages
+/// for which the retry delay has passed.
+///
+/// Must be called regularly while the network is running.
+pub fn poll() {
+    let now = now_ms();
+    loop {
+        let maybe_event = critical_section::with(|cs| OUTBOX.borrow(cs).borrow_mut().pop_event());
+        let Some(event) = maybe_event else {
+            break;
+        };
+        let random = Rng::new().random();
+        critical_section::with(|cs| {
+            let outbox = OUTBOX.borrow(cs);
+            outbox
+                .borrow_mut()
+                .on_sent(event.addr, event.is_ok, now, random);
         });
-    });
+    }
+    flush(now);
+}
+
+pub fn set_policy(policy: RetryPolicy) {
+    critical_section::with(|cs| OUTBOX.borrow(cs).borrow_mut().policy = policy);
 }
 
-/// Get the delivery state of the latest message for the given peer.
 #[must_use]
-pub fn get_status(addr: Addr) -> SendStatus {
-    critical_section::with(|cs| {
-        let states = STATES.borrow(cs);
-        let states = states.borrow();
-        let maybe_state = states.iter().find(|state| state.addr == addr);
-        let Some(state) = maybe_state else {
-            return SendStatus::Empty;
-        };
-        state.status
-    })
+pub fn get_policy() -> RetryPolicy {
+    critical_section::with(|cs| OUTBOX.borrow(cs).borrow().policy)
 }
 
-/// Mark the latest message for the peer as delivered.
-fn confirm(addr: Addr) {
-    set_status(addr, SendStatus::Delivered(0));
-    critical_section::with(|cs| {
-        let pending = PENDING.borrow(cs);
-        let mut pending = pending.borrow_mut();
-        pending.retain(|msg| msg.addr != addr);
-    });
+/// How many reliable messages for the peer are waiting to be sent.
+#[must_use]
+pub fn queued(addr: Addr) -> usize {
+    critical_section::with(|cs| OUTBOX.borrow(cs).borrow().queued(addr))
 }
 
-/// Check if there is already a message for the peer that is sent and waiting for ack.
-fn is_pending(addr: Addr) -> bool {
-    critical_section::with(|cs| {
-        let pending = PENDING.borrow(cs);
-        let pending = pending.borrow();
-        pending.iter().any(|msg| msg.addr == addr)
-    })
+/// How many more messages can be queued for all peers together.
+#[must_use]
+pub fn available() -> usize {
+    critical_section::with(|cs| OUTBOX.borrow(cs).borrow().available())
 }
 
-/// Try re-delivering the latest message for the peer.
-fn retry(addr: Addr) -> Result<(), EspNowError> {
-    let code = critical_section::with(|cs| {
-        let pending = PENDING.borrow(cs);
-        let mut pending = pending.borrow_mut();
-        let mut maybe = pending.iter_mut().find(|msg| msg.addr == addr);
-        let Some(msg) = &mut maybe else {
-            return 0;
-        };
-        msg.attempts += 1;
-        if msg.attempts >= MAX_RETRIES {
-            set_status(addr, SendStatus::Failed);
-            pending.retain(|item| addr != item.addr);
-            0
-        } else {
-            let data = &msg.data;
-            set_status(addr, SendStatus::Sending(msg.attempts));
-            // TODO: move it outside CS.
-            unsafe { esp_now_send(addr.as_ptr(), data.as_ptr(), data.len()) }
+/// Get the delivery state of the latest message for the given peer.
+#[must_use]
+pub fn get_status(addr: Addr) -> SendStatus {
+    critical_section::with(|cs| OUTBOX.borrow(cs).borrow().status(addr))
+}
+
+/// Get the delivery state of the message with the given ID.
+///
+/// Only the most recent messages are remembered. For older ones,
+/// the status is [`SendStatus::Empty`].
+#[must_use]
+pub fn get_message_status(id: u16) -> SendStatus {
+    critical_section::with(|cs| OUTBOX.borrow(cs).borrow().message_status(id))
+}
+
+/// Send the next ready message to every peer that has nothing in flight.
+///
+/// esp-now is called outside of the critical section, so the data
+/// of each message is copied out of the packet pool first.
+fn flush(now: u64) {
+    let mut buf = [0u8; MAX_DATA];
+    let count = critical_section::with(|cs| OUTBOX.borrow(cs).borrow().peers());
+    for index in 0..count {
+        // If a message is refused, the next one for the peer can be sent.
+        loop {
+            let picked = critical_section::with(|cs| {
+                let outbox = OUTBOX.borrow(cs);
+                outbox.borrow_mut().start_sending(index, now, &mut buf)
+            });
+            let Some((addr, id, size)) = picked else {
+                break;
+            };
+            let code = unsafe { esp_now_send(addr.as_ptr(), buf.as_ptr(), size) };
+            if code == 0 {
+                break;
+            }
+            critical_section::with(|cs| OUTBOX.borrow(cs).borrow_mut().on_refused(addr, id));
         }
-    });
-    parse_error_code(code)
+    }
+    critical_section::with(|cs| OUTBOX.borrow(cs).borrow_mut().remove_idle());
 }
 
 /// The callback triggered by esp-now C intrisics on ack/nak of the message.
+///
+/// It runs in the Wi-Fi task, so it only records the event.
+/// The event is handled and the next message is sent by [`poll`].
 unsafe extern "C" fn send_cb(info: *const esp_now_send_info_t, status: esp_now_send_status_t) {
     let is_ok = status == esp_now_send_status_t_ESP_NOW_SEND_SUCCESS;
     let addr: Addr = cast_addr((*info).des_addr);
-    if is_ok {
-        confirm(addr);
-    } else {
-        _ = retry(addr);
-    }
+    let random = Rng::new().random();
+    let now = now_ms();
+    critical_section::with(|cs| {
+        let outbox = OUTBOX.borrow(cs);
+        outbox
+            .borrow_mut()
+            .push_event(Event { addr, is_ok }, now, random);
+    });
 }
 
 /// Read MAC address from memory at the given raw pointer.
@@ -155,19 +158,6 @@ const fn cast_addr(ptr: *mut u8) -> Addr {
     }
 }
 
-// Store the delivery status of the latest message for the peer.
-fn set_status(addr: Addr, send_status: SendStatus) {
-    critical_section::with(|cs| {
-        let states = STATES.borrow(cs);
-        let mut states = states.borrow_mut();
-        states.retain(|state| state.addr != addr);
-        states.push_back(State {
-            addr,
-            status: send_status,
-        });
-    });
-}
-
 /// Convert error code returned by esp-now C library into a Rust-friendly error.
 fn parse_error_code(code: core::ffi::c_int) -> Result<(), EspNowError> {
     if code == 0 {
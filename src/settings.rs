@@ -0,0 +1,106 @@
+//! Persistent settings of the IO chip.
+//!
+//! Stored at the beginning of the "nvs" partition which isn't used
+//! by anything else in this firmware. The layout is the magic number,
+//! the payload size, and then all the fields in order. New fields are
+//! always added at the end, and fields missing in the stored payload
+//! (written by an older firmware) get the default values.
+use crate::{layout::ButtonMap, touch::PadConfig, ErrPrinter};
+use anyhow::{bail, Result};
+use embedded_storage::{ReadStorage, Storage};
+use esp_bootloader_esp_idf::partitions::{
+    read_partition_table, DataPartitionSubType, FlashRegion, PartitionType, PARTITION_TABLE_MAX_LEN,
+};
+use esp_println::println;
+use esp_storage::FlashStorage;
+
+const MAGIC: [u8; 4] = *b"FFIO";
+const HEADER: usize = MAGIC.len() + 1;
+const SIZE: usize = 64;
+
+#[derive(Default)]
+pub struct Settings {
+    pub pad: PadConfig,
+    pub buttons: ButtonMap,
+}
+
+impl Settings {
+    /// Load settings from flash.
+    ///
+    /// If there are no settings stored or they cannot be read, returns the defaults.
+    pub fn load(flash: &mut FlashStorage<'_>) -> Self {
+        let mut raw = [0u8; SIZE];
+        let res = with_region(flash, |region| region.read(0, &mut raw).is_ok());
+        match res {
+            Ok(()) => Self::decode(&raw),
+            Err(err) => {
+                println!("error: {}", ErrPrinter(err));
+                Self::default()
+            }
+        }
+    }
+
+    /// Write settings into flash.
+    pub fn save(&self, flash: &mut FlashStorage<'_>) -> Result<()> {
+        let raw = self.encode();
+        with_region(flash, |region| region.write(0, &raw).is_ok())
+    }
+
+    fn decode(raw: &[u8; SIZE]) -> Self {
+        let mut settings = Self::default();
+        if raw[..MAGIC.len()] != MAGIC {
+            return settings;
+        }
+        let size = usize::from(raw[MAGIC.len()]).min(SIZE - HEADER);
+        let payload = &raw[HEADER..HEADER + size];
+        let Some((pad, payload)) = payload.split_first_chunk() else {
+            return settings;
+        };
+        if let Some(pad) = PadConfig::from_bytes(*pad) {
+            settings.pad = pad;
+        }
+        let Some((buttons, _payload)) = payload.split_first_chunk() else {
+            return settings;
+        };
+        if let Some(buttons) = ButtonMap::from_bytes(*buttons) {
+            settings.buttons = buttons;
+        }
+        settings
+    }
+
+    fn encode(&self) -> [u8; SIZE] {
+        let mut raw = [0u8; SIZE];
+        raw[..MAGIC.len()].copy_from_slice(&MAGIC);
+        let mut size = 0;
+        for field in [&self.pad.to_bytes()[..], &self.buttons.to_bytes()] {
+            let start = HEADER + size;
+            raw[start..start + field.len()].copy_from_slice(field);
+            size += field.len();
+        }
+        #[expect(clippy::cast_possible_truncation)]
+        let size = size as u8;
+        raw[MAGIC.len()] = size;
+        raw
+    }
+}
+
+/// Run the callback on the flash region of the settings partition.
+///
+/// The callback returns false if the flash operation failed.
+fn with_region<'a, F>(flash: &mut FlashStorage<'a>, f: F) -> Result<()>
+where
+    F: FnOnce(&mut FlashRegion<'_, FlashStorage<'a>>) -> bool,
+{
+    let mut buf = [0u8; PARTITION_TABLE_MAX_LEN];
+    let parts = read_partition_table(flash, &mut buf)?;
+    let part_type = PartitionType::Data(DataPartitionSubType::Nvs);
+    let part = parts.find_partition(part_type)?;
+    let Some(part) = part else {
+        bail!("cannot find NVS data partition");
+    };
+    let mut region = part.as_embedded_storage(flash);
+    if !f(&mut region) {
+        bail!("cannot access settings in flash");
+    }
+    Ok(())
+}
@@ -2,20 +2,23 @@ use crate::{
     wifi::{register_wifi_handlers, WifiManager},
     *,
 };
+use alloc::vec::Vec;
 use anyhow::{Context, Result};
 use embedded_hal_bus::spi::ExclusiveDevice;
-use embedded_io::Read;
 use esp_hal::{
     delay::Delay,
-    gpio::{Input, InputConfig, Level, Output, OutputConfig},
+    gpio::{Input, InputConfig, Level, Output, OutputConfig, RtcPinWithResistors},
     peripherals::Peripherals,
+    rtc_cntl::{
+        sleep::{Ext0WakeupSource, WakeupLevel},
+        Rtc,
+    },
     time::Rate,
     timer::timg::TimerGroup,
     uart::Uart,
 };
 use esp_println::println;
 use esp_storage::FlashStorage;
-use firefly_types::{spi::*, Encode};
 
 pub fn run_v1(peripherals: Peripherals) -> Result<()> {
     println!("starting RTOS scheduler...");
@@ -32,6 +35,10 @@ pub fn run_v1(peripherals: Peripherals) -> Result<()> {
         .context("enter sta mode")?;
     let esp_now = interfaces.esp_now;
 
+    println!("loading settings...");
+    let mut flash = FlashStorage::new(peripherals.FLASH);
+    let settings = Settings::load(&mut flash);
+
     println!("configuring touchpad...");
     let pad = {
         let delay = Delay::new();
@@ -51,9 +58,7 @@ pub fn run_v1(peripherals: Peripherals) -> Result<()> {
             .with_mosi(mosi)
             .with_miso(miso);
         let spi_device = ExclusiveDevice::new(spi, cs, delay).context("access spi")?;
-        let mode = cirque_pinnacle::Absolute::default();
-        // TODO(@orsinium): don't unwrap
-        mode.init(spi_device).unwrap()
+        Pinnacle::new(spi_device)
     };
 
     let up = InputConfig::default().with_pull(esp_hal::gpio::Pull::Up);
@@ -63,13 +68,15 @@ pub fn run_v1(peripherals: Peripherals) -> Result<()> {
         w: Input::new(peripherals.GPIO11, up),
         n: Input::new(peripherals.GPIO10, up),
         menu: Input::new(peripherals.GPIO3, up),
+        extra: Vec::new(),
+        axes: Vec::new(),
     };
 
     println!("configuring TCP/IP stack...");
     let wifi = WifiManager::new(interfaces.sta, wifi);
 
-    let flash = FlashStorage::new(peripherals.FLASH);
-    let mut actor = Actor::new(esp_now, pad, buttons, wifi, flash);
+    let power = Power::new(Rtc::new(peripherals.LPWR), Some(deep_sleep));
+    let mut actor = Actor::new(esp_now, pad, buttons, wifi, flash, settings, power);
 
     println!("configuring main SPI...");
     let mut uart_main = {
@@ -83,18 +90,18 @@ pub fn run_v1(peripherals: Peripherals) -> Result<()> {
     };
 
     println!("listening...");
-    let buf = &mut [0u8; 300];
-    loop {
-        // read request size
-        uart_main.read(&mut buf[..1]).context("read request size")?;
-        let size = usize::from(buf[0]);
-
-        // read request payload
-        // TODO(@orsinium): don't unwrap
-        uart_main.read_exact(&mut buf[..size]).unwrap();
-        let req = Request::decode(&buf[..size]).context("decode request")?;
+    serve(&mut uart_main, &mut actor)
+}
 
-        let resp = actor.handle(req);
-        send_resp_buf(&mut uart_main, buf, resp)?;
-    }
+/// Enter deep sleep and wake up when the menu button is pressed.
+fn deep_sleep(rtc: &mut Rtc<'_>) -> ! {
+    // SAFETY: The pin is owned by the menu button input but nothing else
+    // will run after entering deep sleep, and waking up resets the chip.
+    let menu = unsafe { esp_hal::peripherals::GPIO3::steal() };
+    // The button is pressed when the pin is low. In deep sleep, the pin is
+    // controlled by the RTC IO mux, so the digital pull-up doesn't hold it high
+    // and the RTC one must be enabled instead.
+    menu.rtcio_pullup(true);
+    let wakeup = Ext0WakeupSource::new(menu, WakeupLevel::Low);
+    rtc.sleep_deep(&[&wakeup])
 }
@@ -2,20 +2,20 @@ use crate::{
     wifi::{register_wifi_handlers, WifiManager},
     *,
 };
+use alloc::vec::Vec;
 use anyhow::{Context, Result};
 use embedded_hal_bus::spi::ExclusiveDevice;
-use embedded_io::Read;
 use esp_hal::{
     delay::Delay,
     gpio::{Input, InputConfig, Level, Output, OutputConfig},
     peripherals::Peripherals,
+    rtc_cntl::Rtc,
     time::Rate,
     timer::timg::TimerGroup,
     uart::Uart,
 };
 use esp_println::println;
 use esp_storage::FlashStorage;
-use firefly_types::{spi::*, Encode};
 
 pub fn run_v2(peripherals: Peripherals) -> Result<()> {
     println!("starting RTOS scheduler...");
@@ -32,6 +32,10 @@ pub fn run_v2(peripherals: Peripherals) -> Result<()> {
         .context("enter sta mode")?;
     let esp_now = interfaces.esp_now;
 
+    println!("loading settings...");
+    let mut flash = FlashStorage::new(peripherals.FLASH);
+    let settings = Settings::load(&mut flash);
+
     println!("configuring touchpad...");
     let pad = {
         let delay = Delay::new();
@@ -51,9 +55,7 @@ pub fn run_v2(peripherals: Peripherals) -> Result<()> {
             .with_mosi(mosi)
             .with_miso(miso);
         let spi_device = ExclusiveDevice::new(spi, cs, delay).context("access spi")?;
-        let mode = cirque_pinnacle::Absolute::default();
-        // TODO(@orsinium): don't unwrap
-        mode.init(spi_device).unwrap()
+        Pinnacle::new(spi_device)
     };
 
     let up = InputConfig::default().with_pull(esp_hal::gpio::Pull::Up);
@@ -63,13 +65,17 @@ pub fn run_v2(peripherals: Peripherals) -> Result<()> {
         w: Input::new(peripherals.GPIO1, up),
         n: Input::new(peripherals.GPIO44, up),
         menu: Input::new(peripherals.GPIO41, up),
+        extra: Vec::new(),
+        axes: Vec::new(),
     };
 
     println!("configuring TCP/IP stack...");
     let wifi = WifiManager::new(interfaces.sta, wifi);
 
-    let flash = FlashStorage::new(peripherals.FLASH);
-    let mut actor = Actor::new(esp_now, pad, buttons, wifi, flash);
+    // The menu button is on GPIO41 which is not an RTC GPIO,
+    // so it cannot wake the chip up from deep sleep.
+    let power = Power::new(Rtc::new(peripherals.LPWR), None);
+    let mut actor = Actor::new(esp_now, pad, buttons, wifi, flash, settings, power);
 
     println!("configuring main SPI...");
     let mut uart_main = {
@@ -81,17 +87,5 @@ pub fn run_v2(peripherals: Peripherals) -> Result<()> {
     };
 
     println!("listening...");
-    let buf = &mut [0u8; 300];
-    loop {
-        // read request size
-        uart_main.read(&mut buf[..1]).context("read request size")?;
-        let size = usize::from(buf[0]);
-
-        // read request payload
-        // TODO(@orsinium): don't unwrap
-        uart_main.read_exact(&mut buf[..size]).unwrap();
-        let req = Request::decode(&buf[..size]).context("decode request")?;
-        let resp = actor.handle(req);
-        send_resp_buf(&mut uart_main, buf, resp)?;
-    }
+    serve(&mut uart_main, &mut actor)
 }
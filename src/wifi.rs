@@ -1,5 +1,6 @@
 use core::fmt::Display;
 
+use crate::channel::Congestion;
 use alloc::boxed::Box;
 use alloc::string::{String, ToString};
 use alloc::vec;
@@ -13,6 +14,9 @@ use smoltcp::{
     wire::{EthernetAddress, IpAddress, IpCidr, IpEndpoint},
 };
 
+/// Max number of access points to account for when picking a channel.
+const MAX_SCANNED: usize = 32;
+
 static mut WIFI_STATUS: Status = Status::Stopped;
 
 /// Register global handlers for wifi connection updates.
@@ -114,6 +118,25 @@ impl<'a> WifiManager<'a> {
         Ok(ssids)
     }
 
+    /// Scan all channels and measure how congested each of them is.
+    ///
+    /// Takes a while: the scan visits every channel.
+    /// If wifi was stopped before the scan, it is stopped again afterwards.
+    pub fn scan_channels(&mut self) -> Result<Congestion> {
+        let was_started = self.controller.is_started().unwrap_or_default();
+        self.start()?;
+        let config = ScanConfig::default().with_max(MAX_SCANNED);
+        let result = self.controller.scan_with_config(config);
+        if !was_started {
+            self.stop()?;
+        }
+        let mut congestion = Congestion::default();
+        for point in result? {
+            congestion.add(point.channel, point.signal_strength);
+        }
+        Ok(congestion)
+    }
+
     /// Connect to the given wifi Access Point.
     ///
     /// Non-blocking. Check the status to see if connected.